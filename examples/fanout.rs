@@ -0,0 +1,132 @@
+//! Compares the two ways of fanning a channel message out over Redis:
+//! publishing to every member's `U.{id}` topic, or once to `C.{channel_id}`.
+//!
+//! Needs Redis on localhost, and room for `members` extra connections:
+//!
+//! ```sh
+//! cargo run --release --example fanout -- [members] [rounds]
+//! ```
+
+use std::time::{Duration, Instant};
+
+use futures::StreamExt;
+use redis::{AsyncCommands, Client, RedisResult};
+use tokio::sync::mpsc;
+
+const PAYLOAD: &str = r#"{"type":"MESSAGE_CREATE","data":{"id":"cm9v8x0000000000000000000","channel_id":"bench","author_id":"cm9v8x0000000000000000001","content":"The quick brown fox jumps over the lazy dog"}}"#;
+
+#[derive(Clone, Copy)]
+enum Strategy {
+    PerUser,
+    PerChannel,
+}
+
+impl Strategy {
+    fn topic(&self, member: usize) -> String {
+        match self {
+            Strategy::PerUser => format!("U.bench-{}", member),
+            Strategy::PerChannel => "C.bench".to_owned(),
+        }
+    }
+}
+
+struct Stats {
+    /// Time spent issuing the publishes for a message.
+    publish: Duration,
+    /// Time until every member's connection received it.
+    delivery: Duration,
+}
+
+async fn run(
+    client: &Client,
+    strategy: Strategy,
+    members: usize,
+    rounds: usize,
+) -> RedisResult<Stats> {
+    let (tx, mut rx) = mpsc::unbounded_channel::<()>();
+
+    let mut subscribers = Vec::with_capacity(members);
+    for member in 0..members {
+        let mut pubsub = client.get_async_pubsub().await?;
+        pubsub.subscribe(strategy.topic(member)).await?;
+
+        let tx = tx.clone();
+        subscribers.push(tokio::spawn(async move {
+            let mut messages = pubsub.into_on_message();
+            while messages.next().await.is_some() {
+                if tx.send(()).is_err() {
+                    break;
+                }
+            }
+        }));
+    }
+
+    let mut conn = client.get_multiplexed_async_connection().await?;
+    let mut stats = Stats {
+        publish: Duration::ZERO,
+        delivery: Duration::ZERO,
+    };
+
+    for _ in 0..rounds {
+        let start = Instant::now();
+
+        match strategy {
+            // Pipelined, which is as cheap as per-user fan-out gets.
+            Strategy::PerUser => {
+                let mut pipe = redis::pipe();
+                for member in 0..members {
+                    pipe.publish(strategy.topic(member), PAYLOAD).ignore();
+                }
+                pipe.query_async::<()>(&mut conn).await?;
+            }
+            Strategy::PerChannel => conn.publish::<_, _, ()>(strategy.topic(0), PAYLOAD).await?,
+        }
+        stats.publish += start.elapsed();
+
+        for _ in 0..members {
+            rx.recv().await;
+        }
+        stats.delivery += start.elapsed();
+    }
+
+    for subscriber in subscribers {
+        subscriber.abort();
+    }
+
+    Ok(stats)
+}
+
+#[tokio::main]
+async fn main() -> RedisResult<()> {
+    let mut args = std::env::args().skip(1);
+    let members: usize = args.next().and_then(|arg| arg.parse().ok()).unwrap_or(1000);
+    let rounds: usize = args
+        .next()
+        .and_then(|arg| arg.parse().ok())
+        .unwrap_or(100)
+        .max(1);
+
+    let client = Client::open("redis://127.0.0.1/")?;
+
+    println!("{} members, {} messages", members, rounds);
+    println!(
+        "{:<12} {:>10} {:>14} {:>14}",
+        "strategy", "publishes", "publish/msg", "delivery/msg"
+    );
+
+    for (name, strategy, publishes) in [
+        ("per-user", Strategy::PerUser, members),
+        ("per-channel", Strategy::PerChannel, 1),
+    ] {
+        let stats = run(&client, strategy, members, rounds).await?;
+        println!(
+            "{:<12} {:>10} {:>14?} {:>14?}",
+            name,
+            publishes,
+            stats.publish / rounds as u32,
+            stats.delivery / rounds as u32
+        );
+    }
+
+    Ok(())
+}
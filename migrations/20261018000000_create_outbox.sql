@@ -0,0 +1,12 @@
+-- Domain events, written in the same transaction as the change they describe
+-- and relayed to the `events` Redis Stream.
+CREATE TABLE IF NOT EXISTS "outbox" (
+  "id" BIGSERIAL PRIMARY KEY,
+  "event_id" TEXT NOT NULL UNIQUE,
+  "topic" TEXT NOT NULL,
+  "payload" JSONB NOT NULL,
+  "created_at" TIMESTAMPTZ NOT NULL DEFAULT now(),
+  "relayed_at" TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS "outbox_pending_idx" ON "outbox" ("id") WHERE "relayed_at" IS NULL;
@@ -0,0 +1,14 @@
+-- Groups have an owner, who alone can remove members. Existing groups go to
+-- whichever member sorts first, as there is no record of who created them.
+ALTER TABLE "channels" ADD COLUMN IF NOT EXISTS "owner_id" TEXT;
+
+UPDATE "channels" SET "owner_id" = (
+  SELECT MIN("user_id") FROM "channel_members" WHERE "channel_id" = "channels"."id"
+) WHERE "type" = 'GROUP' AND "owner_id" IS NULL;
+
+-- Ownership passes to the longest-standing member when the owner leaves.
+ALTER TABLE "channel_members" ADD COLUMN IF NOT EXISTS "joined_at" TIMESTAMPTZ NOT NULL DEFAULT now();
+
+-- System messages record membership changes in the channel itself, with the
+-- affected user's id as their content.
+ALTER TABLE "messages" ADD COLUMN IF NOT EXISTS "type" TEXT NOT NULL DEFAULT 'DEFAULT';
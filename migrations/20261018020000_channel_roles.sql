@@ -0,0 +1,20 @@
+-- What every member of a group may do, on top of which roles grant more.
+-- 11 is SEND_MESSAGES | ADD_MEMBERS | PIN_MESSAGES.
+ALTER TABLE "channels" ADD COLUMN IF NOT EXISTS "default_permissions" BIGINT NOT NULL DEFAULT 11;
+
+CREATE TABLE IF NOT EXISTS "channel_roles" (
+  "id" TEXT PRIMARY KEY,
+  "channel_id" TEXT NOT NULL REFERENCES "channels" ("id") ON DELETE CASCADE,
+  "name" TEXT NOT NULL,
+  "permissions" BIGINT NOT NULL DEFAULT 0,
+  "created_at" TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS "channel_roles_channel_idx" ON "channel_roles" ("channel_id");
+
+CREATE TABLE IF NOT EXISTS "channel_member_roles" (
+  "channel_id" TEXT NOT NULL,
+  "user_id" TEXT NOT NULL,
+  "role_id" TEXT NOT NULL REFERENCES "channel_roles" ("id") ON DELETE CASCADE,
+  PRIMARY KEY ("channel_id", "user_id", "role_id")
+);
@@ -0,0 +1,13 @@
+-- Shareable codes that let anyone holding one join a group.
+CREATE TABLE IF NOT EXISTS "channel_invites" (
+  "code" TEXT PRIMARY KEY,
+  "channel_id" TEXT NOT NULL REFERENCES "channels" ("id") ON DELETE CASCADE,
+  "inviter_id" TEXT NOT NULL,
+  "max_uses" INTEGER,
+  "uses" INTEGER NOT NULL DEFAULT 0,
+  "expires_at" TIMESTAMPTZ,
+  "revoked_at" TIMESTAMPTZ,
+  "created_at" TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS "channel_invites_channel_idx" ON "channel_invites" ("channel_id");
@@ -0,0 +1,9 @@
+-- Channel metadata anyone in the channel sees.
+ALTER TABLE "channels" ADD COLUMN IF NOT EXISTS "topic" TEXT;
+ALTER TABLE "channels" ADD COLUMN IF NOT EXISTS "icon" TEXT;
+ALTER TABLE "channels" ADD COLUMN IF NOT EXISTS "archived_at" TIMESTAMPTZ;
+
+-- Notification settings of each member: ALL, MENTIONS or NONE, and quiet
+-- until a point in time regardless.
+ALTER TABLE "channel_members" ADD COLUMN IF NOT EXISTS "notify" TEXT NOT NULL DEFAULT 'ALL';
+ALTER TABLE "channel_members" ADD COLUMN IF NOT EXISTS "muted_until" TIMESTAMPTZ;
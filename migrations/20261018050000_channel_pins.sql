@@ -0,0 +1,14 @@
+-- Messages pinned in a channel. Pins reference the message rather than
+-- copying it, so edits show through and deleting it unpins it.
+CREATE TABLE IF NOT EXISTS "channel_pins" (
+  "channel_id" TEXT NOT NULL REFERENCES "channels" ("id") ON DELETE CASCADE,
+  "message_id" TEXT NOT NULL REFERENCES "messages" ("id") ON DELETE CASCADE,
+  "pinned_by" TEXT NOT NULL,
+  "pinned_at" TIMESTAMPTZ NOT NULL DEFAULT now(),
+  PRIMARY KEY ("channel_id", "message_id")
+);
+
+CREATE INDEX IF NOT EXISTS "channel_pins_message_idx" ON "channel_pins" ("message_id");
+
+-- How many messages a channel can have pinned at once.
+ALTER TABLE "channels" ADD COLUMN IF NOT EXISTS "max_pins" INTEGER NOT NULL DEFAULT 50;
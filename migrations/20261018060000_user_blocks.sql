@@ -0,0 +1,10 @@
+-- Users someone blocked. A block ends any friendship between the two and
+-- keeps them from starting a new one or a DM.
+CREATE TABLE IF NOT EXISTS "user_blocks" (
+  "blocker_id" TEXT NOT NULL,
+  "blocked_id" TEXT NOT NULL,
+  "created_at" TIMESTAMPTZ NOT NULL DEFAULT now(),
+  PRIMARY KEY ("blocker_id", "blocked_id")
+);
+
+CREATE INDEX IF NOT EXISTS "user_blocks_blocked_idx" ON "user_blocks" ("blocked_id");
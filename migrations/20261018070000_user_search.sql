@@ -0,0 +1,10 @@
+-- Prefix and fuzzy search on usernames and display names.
+CREATE EXTENSION IF NOT EXISTS pg_trgm;
+
+CREATE INDEX IF NOT EXISTS "users_username_trgm_idx"
+  ON "users" USING GIN (lower("username") gin_trgm_ops);
+CREATE INDEX IF NOT EXISTS "users_display_name_trgm_idx"
+  ON "users" USING GIN (lower("display_name") gin_trgm_ops);
+
+-- Whether people who aren't friends yet can find the user by searching.
+ALTER TABLE "users" ADD COLUMN IF NOT EXISTS "discoverable" BOOLEAN NOT NULL DEFAULT true;
@@ -0,0 +1,7 @@
+-- Who may reach the user: EVERYONE, FRIENDS_OF_FRIENDS or NOBODY.
+ALTER TABLE "users" ADD COLUMN IF NOT EXISTS "friend_requests" TEXT NOT NULL DEFAULT 'EVERYONE';
+ALTER TABLE "users" ADD COLUMN IF NOT EXISTS "direct_messages" TEXT NOT NULL DEFAULT 'EVERYONE';
+
+-- Whether others see when the user was last online, and how far they read.
+ALTER TABLE "users" ADD COLUMN IF NOT EXISTS "show_last_seen" BOOLEAN NOT NULL DEFAULT true;
+ALTER TABLE "users" ADD COLUMN IF NOT EXISTS "read_receipts" BOOLEAN NOT NULL DEFAULT true;
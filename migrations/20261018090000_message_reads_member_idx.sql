@@ -0,0 +1,17 @@
+-- One read marker per member, kept up to date in place. Members who ended
+-- up with several keep the one they read last.
+DELETE FROM "message_reads"
+  WHERE "ctid" IN (
+    SELECT "ctid" FROM (
+      SELECT "ctid", row_number() OVER (
+        PARTITION BY "channel_id", "user_id"
+        ORDER BY "read_at" DESC NULLS LAST, "ctid" DESC
+      ) AS "rank"
+      FROM "message_reads"
+      WHERE "channel_id" IS NOT NULL AND "user_id" IS NOT NULL
+    ) AS "ranked"
+    WHERE "rank" > 1
+  );
+
+CREATE UNIQUE INDEX IF NOT EXISTS "message_reads_member_idx"
+  ON "message_reads" ("channel_id", "user_id");
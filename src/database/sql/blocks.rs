@@ -0,0 +1,170 @@
+use redis::{aio::MultiplexedConnection, AsyncCommands};
+use sqlx::{PgConnection, PgExecutor};
+
+use crate::{
+    features::realtime::{
+        events::{Block, ServerEvent},
+        outbox,
+    },
+    utils::log_error,
+    DB_POOL,
+};
+
+/// Member of every cached block list, so one that's loaded but empty still
+/// exists in Redis. Never a user id.
+const LOADED: &str = "";
+
+/// Users `uid` blocked. Cached as a Redis set, since whether one user blocked
+/// another is checked on every presence update and message they are sent.
+pub async fn get_blocked_ids(conn: &mut MultiplexedConnection, uid: &str) -> Vec<String> {
+    let cached: Vec<String> = conn
+        .smembers(blocks_cache_key(uid))
+        .await
+        .unwrap_or_default();
+
+    if cached.is_empty() {
+        return load_blocked_ids(conn, uid).await;
+    }
+
+    cached.into_iter().filter(|id| id != LOADED).collect()
+}
+
+/// Whether `uid` blocked `other`, without reading their whole list.
+pub async fn has_blocked(conn: &mut MultiplexedConnection, uid: &str, other: &str) -> bool {
+    let key = blocks_cache_key(uid);
+    let (loaded, blocked): (bool, bool) = redis::pipe()
+        .exists(&key)
+        .sismember(&key, other)
+        .query_async(conn)
+        .await
+        .unwrap_or_default();
+
+    if loaded {
+        return blocked;
+    }
+
+    load_blocked_ids(conn, uid)
+        .await
+        .iter()
+        .any(|id| id == other)
+}
+
+async fn load_blocked_ids(conn: &mut MultiplexedConnection, uid: &str) -> Vec<String> {
+    let blocked_res = sqlx::query_scalar::<_, String>(
+        r#"SELECT "blocked_id" FROM "user_blocks" WHERE "blocker_id" = $1"#,
+    )
+    .bind(uid)
+    .fetch_all(DB_POOL.get().unwrap())
+    .await;
+
+    let blocked = match blocked_res {
+        Ok(blocked) => blocked,
+        Err(err) => {
+            log_error("Blocks_LBI", Some(&err));
+            return vec![];
+        }
+    };
+
+    let key = blocks_cache_key(uid);
+    let mut pipe = redis::pipe();
+    pipe.atomic().del(&key).ignore().sadd(&key, LOADED).ignore();
+    if !blocked.is_empty() {
+        pipe.sadd(&key, &blocked).ignore();
+    }
+    pipe.expire(&key, 21600)
+        .ignore()
+        .query_async::<()>(conn)
+        .await
+        .ok();
+
+    blocked
+}
+
+pub fn blocks_cache_key(uid: &str) -> String {
+    format!("CACHE:U_BLOCKS:{}", uid)
+}
+
+/// Whether either of `a` and `b` blocked the other.
+pub async fn is_blocked_between<'c>(
+    executor: impl PgExecutor<'c>,
+    a: &str,
+    b: &str,
+) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar(
+        r#"SELECT EXISTS (
+          SELECT FROM "user_blocks"
+          WHERE ("blocker_id" = $1 AND "blocked_id" = $2)
+            OR ("blocker_id" = $2 AND "blocked_id" = $1)
+        )"#,
+    )
+    .bind(a)
+    .bind(b)
+    .fetch_one(executor)
+    .await
+}
+
+/// Whether `a` and `b` follow each other's presence, as friends or through a
+/// channel they are both in.
+pub async fn are_connected<'c>(
+    executor: impl PgExecutor<'c>,
+    a: &str,
+    b: &str,
+) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar(
+        r#"SELECT EXISTS (
+          SELECT FROM "friends"
+          WHERE "accepted_at" IS NOT NULL
+            AND (("user_a" = $1 AND "user_b" = $2) OR ("user_a" = $2 AND "user_b" = $1))
+        ) OR EXISTS (
+          SELECT FROM "channel_members" AS "a"
+            JOIN "channel_members" AS "b" ON "b"."channel_id" = "a"."channel_id"
+          WHERE "a"."user_id" = $1 AND "b"."user_id" = $2
+        )"#,
+    )
+    .bind(a)
+    .bind(b)
+    .fetch_one(executor)
+    .await
+}
+
+/// Records that `blocker` blocked `blocked` and tells the blocker's sessions.
+/// The blocked user isn't told.
+pub async fn block(tx: &mut PgConnection, blocker: &str, blocked: &str) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"INSERT INTO "user_blocks" ("blocker_id", "blocked_id") VALUES ($1, $2)
+        ON CONFLICT DO NOTHING"#,
+    )
+    .bind(blocker)
+    .bind(blocked)
+    .execute(&mut *tx)
+    .await?;
+
+    let event = ServerEvent::BlockAdd(Block {
+        user_id: blocked.to_owned(),
+    });
+    outbox::enqueue(tx, &format!("U.{}", blocker), &event).await
+}
+
+/// Lifts a block, returning whether there was one.
+pub async fn unblock(
+    tx: &mut PgConnection,
+    blocker: &str,
+    blocked: &str,
+) -> Result<bool, sqlx::Error> {
+    let res =
+        sqlx::query(r#"DELETE FROM "user_blocks" WHERE "blocker_id" = $1 AND "blocked_id" = $2"#)
+            .bind(blocker)
+            .bind(blocked)
+            .execute(&mut *tx)
+            .await?;
+
+    if res.rows_affected() == 0 {
+        return Ok(false);
+    }
+
+    let event = ServerEvent::BlockRemove(Block {
+        user_id: blocked.to_owned(),
+    });
+    outbox::enqueue(tx, &format!("U.{}", blocker), &event).await?;
+    Ok(true)
+}
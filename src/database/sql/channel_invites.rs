@@ -0,0 +1,68 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::PgConnection;
+
+#[derive(sqlx::FromRow, Serialize)]
+pub struct Invite {
+    pub code: String,
+    pub channel_id: String,
+    pub inviter_id: String,
+    pub max_uses: Option<i32>,
+    pub uses: i32,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Condition an invite has to meet to still let people in.
+const USABLE: &str = r#""revoked_at" IS NULL
+  AND ("expires_at" IS NULL OR "expires_at" > now())
+  AND ("max_uses" IS NULL OR "uses" < "max_uses")"#;
+
+/// An invite that can still be used.
+pub async fn get_invite(
+    conn: &mut PgConnection,
+    code: &str,
+) -> Result<Option<Invite>, sqlx::Error> {
+    sqlx::query_as::<_, Invite>(&format!(
+        r#"SELECT "code", "channel_id", "inviter_id", "max_uses", "uses", "expires_at", "created_at"
+        FROM "channel_invites"
+        WHERE "code" = $1 AND {}"#,
+        USABLE
+    ))
+    .bind(code)
+    .fetch_optional(conn)
+    .await
+}
+
+/// Every invite of a channel that can still be used, newest first.
+pub async fn get_channel_invites(
+    conn: &mut PgConnection,
+    channel_id: &str,
+) -> Result<Vec<Invite>, sqlx::Error> {
+    sqlx::query_as::<_, Invite>(&format!(
+        r#"SELECT "code", "channel_id", "inviter_id", "max_uses", "uses", "expires_at", "created_at"
+        FROM "channel_invites"
+        WHERE "channel_id" = $1 AND {}
+        ORDER BY "created_at" DESC"#,
+        USABLE
+    ))
+    .bind(channel_id)
+    .fetch_all(conn)
+    .await
+}
+
+/// Counts one use of an invite, unless it ran out in the meantime. The check
+/// and the increment are one statement, so concurrent accepts can't go over
+/// `max_uses`.
+pub async fn use_invite(tx: &mut PgConnection, code: &str) -> Result<bool, sqlx::Error> {
+    let res = sqlx::query(&format!(
+        r#"UPDATE "channel_invites" SET "uses" = "uses" + 1
+        WHERE "code" = $1 AND {}"#,
+        USABLE
+    ))
+    .bind(code)
+    .execute(tx)
+    .await?;
+
+    Ok(res.rows_affected() == 1)
+}
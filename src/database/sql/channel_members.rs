@@ -0,0 +1,235 @@
+use chrono::{DateTime, Utc};
+use cuid::cuid1;
+use sqlx::PgConnection;
+
+use crate::features::realtime::{
+    events::{Channel, ChannelMember, Message, ServerEvent},
+    outbox,
+};
+
+/// System message kinds, each about the user whose id is the content.
+pub const MEMBER_ADD: &str = "MEMBER_ADD";
+pub const MEMBER_JOIN: &str = "MEMBER_JOIN";
+pub const MEMBER_REMOVE: &str = "MEMBER_REMOVE";
+pub const MEMBER_LEAVE: &str = "MEMBER_LEAVE";
+pub const OWNER_CHANGE: &str = "OWNER_CHANGE";
+
+#[derive(sqlx::FromRow)]
+struct ChannelRow {
+    id: String,
+    name: Option<String>,
+    #[sqlx(rename = "type")]
+    kind: String,
+    owner_id: Option<String>,
+    default_permissions: i64,
+    topic: Option<String>,
+    icon: Option<String>,
+    archived_at: Option<DateTime<Utc>>,
+    max_pins: i32,
+    created_at: DateTime<Utc>,
+}
+
+/// Loads a channel and its members.
+pub async fn get_channel(
+    conn: &mut PgConnection,
+    id: &str,
+) -> Result<Option<Channel>, sqlx::Error> {
+    fetch_channel(conn, id, false).await
+}
+
+/// Like [`get_channel`], holding the channel's row until `tx` ends so
+/// concurrent changes to it queue up behind each other.
+pub async fn lock_channel(tx: &mut PgConnection, id: &str) -> Result<Option<Channel>, sqlx::Error> {
+    fetch_channel(tx, id, true).await
+}
+
+async fn fetch_channel(
+    conn: &mut PgConnection,
+    id: &str,
+    lock: bool,
+) -> Result<Option<Channel>, sqlx::Error> {
+    let query = format!(
+        r#"SELECT "id", "name", "type", "owner_id", "default_permissions", "topic", "icon",
+          "archived_at", "max_pins", "created_at"
+        FROM "channels"
+        WHERE "id" = $1
+        {}"#,
+        if lock { "FOR UPDATE" } else { "" }
+    );
+
+    let row = sqlx::query_as::<_, ChannelRow>(&query)
+        .bind(id)
+        .fetch_optional(&mut *conn)
+        .await?;
+
+    let Some(row) = row else {
+        return Ok(None);
+    };
+
+    let members: Vec<String> = sqlx::query_scalar(
+        r#"SELECT "user_id" FROM "channel_members"
+        WHERE "channel_id" = $1
+        ORDER BY "joined_at", "user_id""#,
+    )
+    .bind(id)
+    .fetch_all(&mut *conn)
+    .await?;
+
+    Ok(Some(Channel {
+        id: row.id,
+        name: row.name,
+        kind: row.kind,
+        owner_id: row.owner_id,
+        default_permissions: row.default_permissions,
+        topic: row.topic,
+        icon: row.icon,
+        archived_at: row.archived_at,
+        max_pins: row.max_pins,
+        created_at: row.created_at,
+        members,
+        settings: None,
+    }))
+}
+
+/// Writes a system message by `author_id` about `user_id` and queues its
+/// `MESSAGE_CREATE`.
+async fn system_message(
+    tx: &mut PgConnection,
+    channel_id: &str,
+    author_id: &str,
+    kind: &str,
+    user_id: &str,
+) -> Result<(), sqlx::Error> {
+    let (id, created_at): (String, DateTime<Utc>) = sqlx::query_as(
+        r#"INSERT INTO "messages" ("id", "channel_id", "author_id", "content", "type", "created_at")
+        VALUES ($1, $2, $3, $4, $5, now())
+        RETURNING "id", "created_at""#,
+    )
+    .bind(cuid1().unwrap())
+    .bind(channel_id)
+    .bind(author_id)
+    .bind(user_id)
+    .bind(kind)
+    .fetch_one(&mut *tx)
+    .await?;
+
+    let event = ServerEvent::MessageCreate(Message {
+        id,
+        channel_id: channel_id.to_owned(),
+        author_id: author_id.to_owned(),
+        content: user_id.to_owned(),
+        kind: kind.to_owned(),
+        reply_to: None,
+        created_at,
+    });
+
+    outbox::enqueue(tx, &format!("C.{}", channel_id), &event).await
+}
+
+/// Adds `user_id` to `channel` on behalf of `actor`, who joined on their own
+/// through an invite when they are the same. Current members hear about it
+/// on the channel's topic, while the new member gets the whole channel on
+/// theirs.
+pub async fn add_member(
+    tx: &mut PgConnection,
+    channel: &mut Channel,
+    actor: &str,
+    user_id: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(r#"INSERT INTO "channel_members" ("channel_id", "user_id") VALUES ($1, $2)"#)
+        .bind(&channel.id)
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+
+    let event = ServerEvent::ChannelMemberAdd(ChannelMember {
+        channel_id: channel.id.clone(),
+        user_id: user_id.to_owned(),
+    });
+    outbox::enqueue(&mut *tx, &format!("C.{}", channel.id), &event).await?;
+
+    channel.members.push(user_id.to_owned());
+    let event = ServerEvent::ChannelCreate(channel.clone());
+    outbox::enqueue(&mut *tx, &format!("U.{}", user_id), &event).await?;
+
+    let kind = if actor == user_id {
+        MEMBER_JOIN
+    } else {
+        MEMBER_ADD
+    };
+    system_message(tx, &channel.id, actor, kind, user_id).await
+}
+
+/// Makes `user_id` the owner of `channel`.
+pub async fn transfer_ownership(
+    tx: &mut PgConnection,
+    channel: &mut Channel,
+    actor: &str,
+    user_id: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(r#"UPDATE "channels" SET "owner_id" = $2 WHERE "id" = $1"#)
+        .bind(&channel.id)
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+
+    channel.owner_id = Some(user_id.to_owned());
+    let event = ServerEvent::ChannelUpdate(channel.clone());
+    outbox::enqueue(&mut *tx, &format!("C.{}", channel.id), &event).await?;
+
+    system_message(tx, &channel.id, actor, OWNER_CHANGE, user_id).await
+}
+
+/// Takes `user_id` out of `channel`, who left on their own when they are the
+/// `actor`. An owner leaving hands the group to the longest-standing member
+/// left, and the last one out leaves it empty and without an owner.
+pub async fn remove_member(
+    tx: &mut PgConnection,
+    channel: &mut Channel,
+    actor: &str,
+    user_id: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(r#"DELETE FROM "channel_members" WHERE "channel_id" = $1 AND "user_id" = $2"#)
+        .bind(&channel.id)
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+
+    // Roles don't follow someone who comes back later.
+    sqlx::query(r#"DELETE FROM "channel_member_roles" WHERE "channel_id" = $1 AND "user_id" = $2"#)
+        .bind(&channel.id)
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+
+    // Published before the member unsubscribes, so they hear it too.
+    let event = ServerEvent::ChannelMemberRemove(ChannelMember {
+        channel_id: channel.id.clone(),
+        user_id: user_id.to_owned(),
+    });
+    outbox::enqueue(&mut *tx, &format!("C.{}", channel.id), &event).await?;
+
+    channel.members.retain(|member| member != user_id);
+
+    let kind = if actor == user_id {
+        MEMBER_LEAVE
+    } else {
+        MEMBER_REMOVE
+    };
+    system_message(tx, &channel.id, actor, kind, user_id).await?;
+
+    if channel.owner_id.as_deref() == Some(user_id) {
+        if let Some(successor) = channel.members.first().cloned() {
+            transfer_ownership(tx, channel, actor, &successor).await?;
+        } else {
+            sqlx::query(r#"UPDATE "channels" SET "owner_id" = NULL WHERE "id" = $1"#)
+                .bind(&channel.id)
+                .execute(&mut *tx)
+                .await?;
+
+            channel.owner_id = None;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,71 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::PgConnection;
+
+use crate::features::realtime::{
+    events::{ChannelPins, Message, Pin, ServerEvent},
+    outbox,
+};
+
+/// A pinned message as it reads now, with who pinned it and when.
+#[derive(sqlx::FromRow, Serialize)]
+pub struct PinnedMessage {
+    #[sqlx(flatten)]
+    #[serde(flatten)]
+    pub message: Message,
+    pub pinned_by: String,
+    pub pinned_at: DateTime<Utc>,
+}
+
+/// Every pin of a channel, most recently pinned first. Pins of deleted
+/// messages go with them.
+pub async fn get_pins(
+    conn: &mut PgConnection,
+    channel_id: &str,
+) -> Result<ChannelPins, sqlx::Error> {
+    let pins = sqlx::query_as::<_, Pin>(
+        r#"SELECT "message_id", "pinned_by", "pinned_at" FROM "channel_pins"
+        WHERE "channel_id" = $1
+        ORDER BY "pinned_at" DESC, "message_id""#,
+    )
+    .bind(channel_id)
+    .fetch_all(conn)
+    .await?;
+
+    Ok(ChannelPins {
+        channel_id: channel_id.to_owned(),
+        pins,
+    })
+}
+
+/// The pinned messages of a channel, in the same order as [`get_pins`].
+pub async fn get_pinned_messages(
+    conn: &mut PgConnection,
+    channel_id: &str,
+) -> Result<Vec<PinnedMessage>, sqlx::Error> {
+    sqlx::query_as::<_, PinnedMessage>(
+        r#"SELECT "m"."id", "m"."channel_id", "m"."author_id", "m"."content", "m"."type",
+          "m"."reply_to", "m"."created_at", "p"."pinned_by", "p"."pinned_at"
+        FROM "channel_pins" AS "p"
+          JOIN "messages" AS "m" ON "m"."id" = "p"."message_id"
+        WHERE "p"."channel_id" = $1
+        ORDER BY "p"."pinned_at" DESC, "p"."message_id""#,
+    )
+    .bind(channel_id)
+    .fetch_all(conn)
+    .await
+}
+
+/// Queues a `CHANNEL_PINS_UPDATE` with the pins as they stand in `tx`, and
+/// returns them.
+pub async fn publish_pins(
+    tx: &mut PgConnection,
+    channel_id: &str,
+) -> Result<ChannelPins, sqlx::Error> {
+    let pins = get_pins(&mut *tx, channel_id).await?;
+
+    let event = ServerEvent::ChannelPinsUpdate(pins.clone());
+    outbox::enqueue(&mut *tx, &format!("C.{}", channel_id), &event).await?;
+
+    Ok(pins)
+}
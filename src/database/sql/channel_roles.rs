@@ -0,0 +1,73 @@
+use std::collections::HashMap;
+
+use sqlx::PgConnection;
+
+use crate::features::realtime::{
+    events::{Channel, ChannelRoles, Role, ServerEvent},
+    outbox,
+};
+
+/// Every role of a channel, oldest first, and who holds them.
+pub async fn get_roles(
+    conn: &mut PgConnection,
+    channel_id: &str,
+) -> Result<ChannelRoles, sqlx::Error> {
+    let roles = sqlx::query_as::<_, Role>(
+        r#"SELECT "id", "name", "permissions", "created_at" FROM "channel_roles"
+        WHERE "channel_id" = $1
+        ORDER BY "created_at", "id""#,
+    )
+    .bind(channel_id)
+    .fetch_all(&mut *conn)
+    .await?;
+
+    let assignments: Vec<(String, String)> = sqlx::query_as(
+        r#"SELECT "user_id", "role_id" FROM "channel_member_roles"
+        WHERE "channel_id" = $1"#,
+    )
+    .bind(channel_id)
+    .fetch_all(&mut *conn)
+    .await?;
+
+    let mut members: HashMap<String, Vec<String>> = HashMap::new();
+    for (user_id, role_id) in assignments {
+        members.entry(user_id).or_default().push(role_id);
+    }
+
+    Ok(ChannelRoles {
+        channel_id: channel_id.to_owned(),
+        roles,
+        members,
+    })
+}
+
+/// Queues a `CHANNEL_ROLES_UPDATE` with the roles as they stand in `tx`,
+/// and returns them.
+pub async fn publish_roles(
+    tx: &mut PgConnection,
+    channel_id: &str,
+) -> Result<ChannelRoles, sqlx::Error> {
+    let roles = get_roles(&mut *tx, channel_id).await?;
+
+    let event = ServerEvent::ChannelRolesUpdate(roles.clone());
+    outbox::enqueue(&mut *tx, &format!("C.{}", channel_id), &event).await?;
+
+    Ok(roles)
+}
+
+/// Sets what every member of `channel` may do without a role.
+pub async fn update_default_permissions(
+    tx: &mut PgConnection,
+    channel: &mut Channel,
+    permissions: i64,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(r#"UPDATE "channels" SET "default_permissions" = $2 WHERE "id" = $1"#)
+        .bind(&channel.id)
+        .bind(permissions)
+        .execute(&mut *tx)
+        .await?;
+
+    channel.default_permissions = permissions;
+    let event = ServerEvent::ChannelUpdate(channel.clone());
+    outbox::enqueue(tx, &format!("C.{}", channel.id), &event).await
+}
@@ -0,0 +1,73 @@
+use chrono::{DateTime, Utc};
+use sqlx::PgConnection;
+
+use crate::features::realtime::{
+    events::{Channel, MemberSettings, Notify, ServerEvent},
+    outbox,
+};
+
+/// Saves the name, topic, icon, pin cap and archived state of `channel` and
+/// tells its members.
+pub async fn update_channel(tx: &mut PgConnection, channel: &Channel) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"UPDATE "channels"
+        SET "name" = $2, "topic" = $3, "icon" = $4, "archived_at" = $5, "max_pins" = $6
+        WHERE "id" = $1"#,
+    )
+    .bind(&channel.id)
+    .bind(&channel.name)
+    .bind(&channel.topic)
+    .bind(&channel.icon)
+    .bind(channel.archived_at)
+    .bind(channel.max_pins)
+    .execute(&mut *tx)
+    .await?;
+
+    let event = ServerEvent::ChannelUpdate(channel.clone());
+    outbox::enqueue(tx, &format!("C.{}", channel.id), &event).await
+}
+
+/// How `user_id` wants to hear about `channel_id`, if they are a member.
+pub async fn get_member_settings(
+    conn: &mut PgConnection,
+    channel_id: &str,
+    user_id: &str,
+) -> Result<Option<MemberSettings>, sqlx::Error> {
+    let row: Option<(String, Option<DateTime<Utc>>)> = sqlx::query_as(
+        r#"SELECT "notify", "muted_until" FROM "channel_members"
+        WHERE "channel_id" = $1 AND "user_id" = $2"#,
+    )
+    .bind(channel_id)
+    .bind(user_id)
+    .fetch_optional(conn)
+    .await?;
+
+    Ok(row.map(|(notify, muted_until)| MemberSettings {
+        notify: Notify::parse(&notify).unwrap_or(Notify::All),
+        muted_until,
+    }))
+}
+
+/// Saves the settings of `user_id` in `channel` and sends them the channel
+/// with them, to their sessions only.
+pub async fn update_member_settings(
+    tx: &mut PgConnection,
+    channel: &mut Channel,
+    user_id: &str,
+    settings: MemberSettings,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"UPDATE "channel_members" SET "notify" = $3, "muted_until" = $4
+        WHERE "channel_id" = $1 AND "user_id" = $2"#,
+    )
+    .bind(&channel.id)
+    .bind(user_id)
+    .bind(settings.notify.as_str())
+    .bind(settings.muted_until)
+    .execute(&mut *tx)
+    .await?;
+
+    channel.settings = Some(settings);
+    let event = ServerEvent::ChannelUpdate(channel.clone());
+    outbox::enqueue(tx, &format!("U.{}", user_id), &event).await
+}
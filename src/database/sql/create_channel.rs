@@ -0,0 +1,64 @@
+use chrono::{DateTime, Utc};
+use cuid::cuid1;
+use sqlx::PgConnection;
+
+use crate::features::realtime::{events::Channel, events::ServerEvent, outbox};
+
+/// One-to-one conversation between two users.
+pub const DM: &str = "DM";
+
+/// Group chat with a name and any number of friends.
+pub const GROUP: &str = "GROUP";
+
+/// Creates a channel with `members` and queues a `CHANNEL_CREATE` for each of
+/// them, all as part of the transaction `tx`.
+pub async fn create_channel(
+    tx: &mut PgConnection,
+    kind: &str,
+    name: Option<&str>,
+    owner: Option<&str>,
+    members: &[String],
+) -> Result<Channel, sqlx::Error> {
+    let (id, default_permissions, max_pins, created_at): (String, i64, i32, DateTime<Utc>) =
+        sqlx::query_as(
+            r#"INSERT INTO "channels" ("id", "name", "type", "owner_id") VALUES ($1, $2, $3, $4)
+            RETURNING "id", "default_permissions", "max_pins", "created_at""#,
+        )
+        .bind(cuid1().unwrap())
+        .bind(name)
+        .bind(kind)
+        .bind(owner)
+        .fetch_one(&mut *tx)
+        .await?;
+
+    sqlx::query(
+        r#"INSERT INTO "channel_members" ("channel_id", "user_id")
+        SELECT $1, UNNEST($2::text[])"#,
+    )
+    .bind(&id)
+    .bind(members)
+    .execute(&mut *tx)
+    .await?;
+
+    let channel = Channel {
+        id,
+        name: name.map(str::to_owned),
+        kind: kind.to_owned(),
+        owner_id: owner.map(str::to_owned),
+        default_permissions,
+        topic: None,
+        icon: None,
+        archived_at: None,
+        max_pins,
+        created_at,
+        members: members.to_vec(),
+        settings: None,
+    };
+
+    let event = ServerEvent::ChannelCreate(channel.clone());
+    for uid in members {
+        outbox::enqueue(&mut *tx, &format!("U.{}", uid), &event).await?;
+    }
+
+    Ok(channel)
+}
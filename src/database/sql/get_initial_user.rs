@@ -7,6 +7,7 @@ use serde_json::{json, Map, Value};
 use sqlx::{Pool, Postgres};
 
 use crate::{
+    database::sql::{blocks::get_blocked_ids, privacy::load_privacy},
     utils::{cached_query, log_error},
     DB_POOL, RD_POOL,
 };
@@ -32,26 +33,26 @@ struct Friend {
     created_at: DateTime<Utc>,
 }
 
+pub fn me_user_cache_key(uid: &str) -> String {
+    format!("CACHE:ME_USER:{}", uid)
+}
+
 async fn get_me_user(id: &String, client: &Client, pool: &Pool<Postgres>) -> Value {
     let mut conn = client.get_multiplexed_async_connection().await.unwrap();
 
-    cached_query(
-        &mut conn,
-        &format!("CACHE:ME_USER:{}", &id),
-        21600,
-        || async {
-            let user_me_res = sqlx::query_as::<_, User>(
-                "SELECT id, display_name, username FROM users WHERE id = $1",
-            )
-            .bind(&id)
-            .fetch_one(pool)
-            .await;
-            if user_me_res.is_err() {
-                log_error("GetIntialUser_GMU_UMR", user_me_res.as_ref().err());
-            }
-            json!(user_me_res.unwrap())
-        },
-    )
+    cached_query(&mut conn, &me_user_cache_key(id), 21600, || async {
+        let user_me_res =
+            sqlx::query_as::<_, User>("SELECT id, display_name, username FROM users WHERE id = $1")
+                .bind(id)
+                .fetch_one(pool)
+                .await;
+        if user_me_res.is_err() {
+            log_error("GetIntialUser_GMU_UMR", user_me_res.as_ref().err());
+        }
+        let mut me = json!(user_me_res.unwrap());
+        me["privacy"] = json!(load_privacy(id).await);
+        me
+    })
     .await
 }
 
@@ -75,7 +76,7 @@ async fn get_user_relationships(
                 OR "user_b" = $1
               )"#,
             )
-            .bind(&id)
+            .bind(id)
             .fetch_all(pool)
             .await;
             if user_friends_res.is_err() {
@@ -108,6 +109,11 @@ async fn get_user_relationships(
         });
     });
 
+    // Blocked users come along flagged, so clients can hide their messages
+    // in shared groups and list them for unblocking.
+    let blocked = get_blocked_ids(&mut conn, id).await;
+    users_to_load.extend(blocked.iter().cloned());
+
     let mut users_list: Vec<String> = users_to_load.iter().cloned().collect();
 
     users_list.push("".to_owned());
@@ -138,7 +144,13 @@ async fn get_user_relationships(
 
     let users: Map<String, Value> = users_ss
         .iter()
-        .map(|user| (user.id.clone(), json!(user)))
+        .map(|user| {
+            let mut value = json!(user);
+            if blocked.contains(&user.id) {
+                value["blocked"] = json!(true);
+            }
+            (user.id.clone(), value)
+        })
         .collect();
 
     let relationships: Map<String, Value> = user_friends
@@ -180,7 +192,14 @@ async fn get_channels(id: &String, client: &Client, pool: &Pool<Postgres>) -> Va
             "c"."id",
             "c"."name",
             "c"."type",
+            "c"."owner_id",
+            "c"."default_permissions",
+            "c"."topic",
+            "c"."icon",
+            "c"."archived_at",
+            "c"."max_pins",
             "c"."created_at",
+            json_build_object('notify', "cm"."notify", 'muted_until', "cm"."muted_until") AS "settings",
             "message_reads"."read_at",
             "message_reads"."last_read_message_id",
             array_agg("members"."user_id") AS "members",
@@ -194,7 +213,8 @@ async fn get_channels(id: &String, client: &Client, pool: &Pool<Postgres>) -> Va
                     "lm"."created_at",
                     "lm"."channel_id",
                     "lm"."reply_to",
-                    "lm"."author_id"
+                    "lm"."author_id",
+                    "lm"."type"
                   FROM "messages" AS "lm"
                   WHERE "lm"."channel_id" = "c"."id"
                   ORDER BY "lm"."created_at" DESC
@@ -212,20 +232,29 @@ async fn get_channels(id: &String, client: &Client, pool: &Pool<Postgres>) -> Va
             LEFT JOIN "channels" AS "c" ON "cm"."channel_id" = "c"."id"
             LEFT JOIN "channel_members" AS "members" ON "members"."channel_id" = "cm"."channel_id"
             LEFT JOIN "message_reads" ON "message_reads"."channel_id" = "cm"."channel_id"
+              AND "message_reads"."user_id" = "cm"."user_id"
           WHERE
             "cm"."user_id" = $1
           GROUP BY
             "c"."id",
             "c"."name",
             "c"."type",
+            "c"."owner_id",
+            "c"."default_permissions",
+            "c"."topic",
+            "c"."icon",
+            "c"."archived_at",
+            "c"."max_pins",
             "c"."created_at",
             "message_reads"."read_at",
             "cm"."channel_id",
+            "cm"."notify",
+            "cm"."muted_until",
             "message_reads"."last_read_message_id"
           ORDER BY
             "c"."created_at" DESC) AS sub"#,
             )
-            .bind(&id)
+            .bind(id)
             .fetch_one(pool)
             .await;
 
@@ -248,7 +277,7 @@ async fn get_channels(id: &String, client: &Client, pool: &Pool<Postgres>) -> Va
         })
         .collect();
 
-    return Value::Object(map);
+    Value::Object(map)
 }
 
 pub async fn get_initial_user(id: &String) -> Value {
@@ -257,10 +286,10 @@ pub async fn get_initial_user(id: &String) -> Value {
     let pool = DB_POOL.get().unwrap();
 
     let (me, channels) = tokio::join!(
-        get_me_user(&id, client, pool),
-        get_channels(&id, client, pool),
+        get_me_user(id, client, pool),
+        get_channels(id, client, pool),
     );
-    let mut relationships = get_user_relationships(&id, &channels, client, &pool).await;
+    let mut relationships = get_user_relationships(id, &channels, client, pool).await;
 
     let partial = json!({
       "me": me,
@@ -271,5 +300,5 @@ pub async fn get_initial_user(id: &String) -> Value {
         a_map.extend(b_map);
     }
 
-    return relationships;
+    relationships
 }
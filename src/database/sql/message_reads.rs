@@ -0,0 +1,75 @@
+use chrono::{DateTime, Utc};
+use sqlx::PgConnection;
+
+use crate::features::realtime::{
+    events::{MessageAck, ServerEvent},
+    outbox,
+};
+
+/// Moves the read marker of `user_id` in `channel_id` to `message_id` and
+/// queues a `MESSAGE_ACK`, for the whole channel only when they `share` it.
+///
+/// The marker only moves forward, so a late ack for an older message
+/// changes nothing and returns `None`.
+pub async fn mark_read(
+    tx: &mut PgConnection,
+    channel_id: &str,
+    user_id: &str,
+    message_id: &str,
+    share: bool,
+) -> Result<Option<DateTime<Utc>>, sqlx::Error> {
+    let read_at: Option<DateTime<Utc>> = sqlx::query_scalar(
+        r#"INSERT INTO "message_reads" ("channel_id", "user_id", "read_at", "last_read_message_id")
+        VALUES ($1, $2, now(), $3)
+        ON CONFLICT ("channel_id", "user_id") DO UPDATE
+        SET "read_at" = EXCLUDED."read_at", "last_read_message_id" = EXCLUDED."last_read_message_id"
+        WHERE NOT EXISTS (
+          SELECT FROM "messages" AS "old", "messages" AS "new"
+          WHERE "old"."id" = "message_reads"."last_read_message_id"
+            AND "new"."id" = EXCLUDED."last_read_message_id"
+            AND ("old"."created_at", "old"."id") >= ("new"."created_at", "new"."id")
+        )
+        RETURNING "read_at""#,
+    )
+    .bind(channel_id)
+    .bind(user_id)
+    .bind(message_id)
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    let Some(read_at) = read_at else {
+        return Ok(None);
+    };
+
+    let topic = if share {
+        format!("C.{}", channel_id)
+    } else {
+        format!("U.{}", user_id)
+    };
+
+    let event = ServerEvent::MessageAck(MessageAck {
+        channel_id: channel_id.to_owned(),
+        user_id: user_id.to_owned(),
+        message_id: message_id.to_owned(),
+        read_at,
+    });
+    outbox::enqueue(tx, &topic, &event).await?;
+
+    Ok(Some(read_at))
+}
+
+/// The message `user_id` read `channel_id` up to, and when.
+pub async fn get_read_marker(
+    conn: &mut PgConnection,
+    channel_id: &str,
+    user_id: &str,
+) -> Result<Option<(Option<String>, Option<DateTime<Utc>>)>, sqlx::Error> {
+    sqlx::query_as(
+        r#"SELECT "last_read_message_id", "read_at" FROM "message_reads"
+        WHERE "channel_id" = $1 AND "user_id" = $2"#,
+    )
+    .bind(channel_id)
+    .bind(user_id)
+    .fetch_optional(conn)
+    .await
+}
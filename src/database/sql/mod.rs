@@ -1 +1,12 @@
-pub mod get_initial_user;
\ No newline at end of file
+pub mod blocks;
+pub mod channel_invites;
+pub mod channel_members;
+pub mod channel_pins;
+pub mod channel_roles;
+pub mod channel_settings;
+pub mod create_channel;
+pub mod get_initial_user;
+pub mod message_reads;
+pub mod privacy;
+pub mod relationships;
+pub mod users;
\ No newline at end of file
@@ -0,0 +1,203 @@
+use redis::aio::MultiplexedConnection;
+use serde::{Deserialize, Serialize};
+use sqlx::PgExecutor;
+
+use crate::{
+    utils::{cached_query, log_error},
+    DB_POOL,
+};
+
+/// Who a user lets reach them in some way. Friends always can.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum Audience {
+    Everyone,
+    /// People sharing at least one friend with the user.
+    FriendsOfFriends,
+    Nobody,
+}
+
+impl Audience {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Audience::Everyone => "EVERYONE",
+            Audience::FriendsOfFriends => "FRIENDS_OF_FRIENDS",
+            Audience::Nobody => "NOBODY",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "EVERYONE" => Some(Audience::Everyone),
+            "FRIENDS_OF_FRIENDS" => Some(Audience::FriendsOfFriends),
+            "NOBODY" => Some(Audience::Nobody),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Privacy {
+    /// Whether people who aren't friends yet can find them by searching.
+    pub discoverable: bool,
+    pub friend_requests: Audience,
+    pub direct_messages: Audience,
+    /// Whether others see `lastSeen` in their presence.
+    pub show_last_seen: bool,
+    /// Whether other members learn how far they read.
+    pub read_receipts: bool,
+}
+
+impl Default for Privacy {
+    fn default() -> Self {
+        Privacy {
+            discoverable: true,
+            friend_requests: Audience::Everyone,
+            direct_messages: Audience::Everyone,
+            show_last_seen: true,
+            read_receipts: true,
+        }
+    }
+}
+
+/// Settings to change, with those left out staying as they are.
+#[derive(Deserialize)]
+pub struct PrivacyUpdate {
+    pub discoverable: Option<bool>,
+    pub friend_requests: Option<Audience>,
+    pub direct_messages: Option<Audience>,
+    pub show_last_seen: Option<bool>,
+    pub read_receipts: Option<bool>,
+}
+
+#[derive(sqlx::FromRow)]
+struct PrivacyRow {
+    discoverable: bool,
+    friend_requests: String,
+    direct_messages: String,
+    show_last_seen: bool,
+    read_receipts: bool,
+}
+
+impl From<PrivacyRow> for Privacy {
+    fn from(row: PrivacyRow) -> Self {
+        Privacy {
+            discoverable: row.discoverable,
+            friend_requests: Audience::parse(&row.friend_requests).unwrap_or(Audience::Everyone),
+            direct_messages: Audience::parse(&row.direct_messages).unwrap_or(Audience::Everyone),
+            show_last_seen: row.show_last_seen,
+            read_receipts: row.read_receipts,
+        }
+    }
+}
+
+pub fn privacy_cache_key(uid: &str) -> String {
+    format!("CACHE:U_PRIVACY:{}", uid)
+}
+
+/// The privacy settings of `uid`. `INIT` caches them as part of
+/// `CACHE:ME_USER`, and they're cached on their own as well since presence
+/// reads them on every update.
+pub async fn get_privacy(conn: &mut MultiplexedConnection, uid: &str) -> Privacy {
+    cached_query(conn, &privacy_cache_key(uid), 21600, || load_privacy(uid)).await
+}
+
+/// The privacy settings of `uid` straight from SQL.
+pub async fn load_privacy(uid: &str) -> Privacy {
+    let privacy_res = sqlx::query_as::<_, PrivacyRow>(
+        r#"SELECT "discoverable", "friend_requests", "direct_messages", "show_last_seen",
+          "read_receipts"
+        FROM "users" WHERE "id" = $1"#,
+    )
+    .bind(uid)
+    .fetch_optional(DB_POOL.get().unwrap())
+    .await;
+
+    match privacy_res {
+        Ok(row) => row.map(Privacy::from).unwrap_or_default(),
+        Err(err) => {
+            log_error("Privacy_LP", Some(&err));
+            Privacy::default()
+        }
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct UpdatedPrivacyRow {
+    #[sqlx(flatten)]
+    privacy: PrivacyRow,
+    showed_last_seen: bool,
+}
+
+/// Applies `update` to the settings of `uid`. Returns the settings now in
+/// place and whether `lastSeen` was shown before.
+///
+/// Fields are merged in SQL, so concurrent updates of different settings
+/// don't undo each other.
+pub async fn update_privacy<'c>(
+    executor: impl PgExecutor<'c>,
+    uid: &str,
+    update: &PrivacyUpdate,
+) -> Result<(Privacy, bool), sqlx::Error> {
+    let row = sqlx::query_as::<_, UpdatedPrivacyRow>(
+        r#"WITH "previous" AS (
+          SELECT "show_last_seen" FROM "users" WHERE "id" = $1 FOR UPDATE
+        )
+        UPDATE "users"
+        SET "discoverable" = COALESCE($2, "users"."discoverable"),
+          "friend_requests" = COALESCE($3, "users"."friend_requests"),
+          "direct_messages" = COALESCE($4, "users"."direct_messages"),
+          "show_last_seen" = COALESCE($5, "users"."show_last_seen"),
+          "read_receipts" = COALESCE($6, "users"."read_receipts")
+        FROM "previous"
+        WHERE "users"."id" = $1
+        RETURNING "users"."discoverable", "users"."friend_requests", "users"."direct_messages",
+          "users"."show_last_seen", "users"."read_receipts",
+          "previous"."show_last_seen" AS "showed_last_seen""#,
+    )
+    .bind(uid)
+    .bind(update.discoverable)
+    .bind(update.friend_requests.map(|audience| audience.as_str()))
+    .bind(update.direct_messages.map(|audience| audience.as_str()))
+    .bind(update.show_last_seen)
+    .bind(update.read_receipts)
+    .fetch_one(executor)
+    .await?;
+
+    Ok((row.privacy.into(), row.showed_last_seen))
+}
+
+/// Whether `audience`, as set by `owner`, lets `other` in. Friends of the
+/// owner count as friends of friends.
+pub async fn admits<'c>(
+    executor: impl PgExecutor<'c>,
+    audience: Audience,
+    owner: &str,
+    other: &str,
+) -> Result<bool, sqlx::Error> {
+    match audience {
+        Audience::Everyone => Ok(true),
+        Audience::Nobody => Ok(false),
+        Audience::FriendsOfFriends => {
+            sqlx::query_scalar(
+                r#"WITH "f" AS (
+                  SELECT "user_a", "user_b" FROM "friends" WHERE "accepted_at" IS NOT NULL
+                ),
+                "owner_friends" AS (
+                  SELECT CASE WHEN "user_a" = $1 THEN "user_b" ELSE "user_a" END AS "id"
+                  FROM "f" WHERE "user_a" = $1 OR "user_b" = $1
+                ),
+                "other_friends" AS (
+                  SELECT CASE WHEN "user_a" = $2 THEN "user_b" ELSE "user_a" END AS "id"
+                  FROM "f" WHERE "user_a" = $2 OR "user_b" = $2
+                )
+                SELECT $2 IN (SELECT "id" FROM "owner_friends")
+                  OR EXISTS (SELECT FROM "owner_friends" JOIN "other_friends" USING ("id"))"#,
+            )
+            .bind(owner)
+            .bind(other)
+            .fetch_one(executor)
+            .await
+        }
+    }
+}
@@ -0,0 +1,144 @@
+use chrono::{DateTime, Utc};
+use cuid::cuid1;
+use sqlx::{PgConnection, PgExecutor};
+
+use crate::features::realtime::{
+    events::{Relationship, ServerEvent},
+    outbox,
+};
+
+/// A row of `friends`: a request from `user_a` to `user_b`, accepted or not.
+#[derive(sqlx::FromRow)]
+pub struct Friendship {
+    pub id: String,
+    pub user_a: String,
+    pub user_b: String,
+    pub accepted_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl Friendship {
+    /// How the friendship looks to `uid`, one of its two sides.
+    pub fn relationship(&self, uid: &str) -> Relationship {
+        Relationship {
+            id: self.id.clone(),
+            user_id: if self.user_a == uid {
+                self.user_b.clone()
+            } else {
+                self.user_a.clone()
+            },
+            incoming: self.user_a != uid,
+            accepted_at: self.accepted_at,
+            created_at: self.created_at,
+        }
+    }
+
+    pub fn users(&self) -> Vec<String> {
+        vec![self.user_a.clone(), self.user_b.clone()]
+    }
+}
+
+/// Serializes changes between two users until `tx` ends and returns their
+/// friendship or pending request, whichever way it goes.
+pub async fn lock_friendship(
+    tx: &mut PgConnection,
+    a: &str,
+    b: &str,
+) -> Result<Option<Friendship>, sqlx::Error> {
+    let (first, second) = if a < b { (a, b) } else { (b, a) };
+    sqlx::query("SELECT pg_advisory_xact_lock(hashtext($1))")
+        .bind(format!("friends:{}:{}", first, second))
+        .execute(&mut *tx)
+        .await?;
+
+    sqlx::query_as::<_, Friendship>(
+        r#"SELECT "id", "user_a", "user_b", "accepted_at", "created_at" FROM "friends"
+        WHERE ("user_a" = $1 AND "user_b" = $2) OR ("user_a" = $2 AND "user_b" = $1)
+        LIMIT 1"#,
+    )
+    .bind(a)
+    .bind(b)
+    .fetch_optional(&mut *tx)
+    .await
+}
+
+/// Whether `a` and `b` are friends, request accepted.
+pub async fn are_friends<'c>(
+    executor: impl PgExecutor<'c>,
+    a: &str,
+    b: &str,
+) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar(
+        r#"SELECT EXISTS (
+          SELECT FROM "friends"
+          WHERE "accepted_at" IS NOT NULL
+            AND (("user_a" = $1 AND "user_b" = $2) OR ("user_a" = $2 AND "user_b" = $1))
+        )"#,
+    )
+    .bind(a)
+    .bind(b)
+    .fetch_one(executor)
+    .await
+}
+
+/// Queues `event` for both sides, each with the friendship as they see it.
+async fn publish(
+    tx: &mut PgConnection,
+    friendship: &Friendship,
+    event: fn(Relationship) -> ServerEvent,
+) -> Result<(), sqlx::Error> {
+    for uid in [&friendship.user_a, &friendship.user_b] {
+        let relationship = event(friendship.relationship(uid));
+        outbox::enqueue(&mut *tx, &format!("U.{}", uid), &relationship).await?;
+    }
+    Ok(())
+}
+
+/// Records a friend request from `from` to `to`.
+pub async fn create_request(
+    tx: &mut PgConnection,
+    from: &str,
+    to: &str,
+) -> Result<Friendship, sqlx::Error> {
+    let friendship = sqlx::query_as::<_, Friendship>(
+        r#"INSERT INTO "friends" ("id", "user_a", "user_b", "created_at") VALUES ($1, $2, $3, now())
+        RETURNING "id", "user_a", "user_b", "accepted_at", "created_at""#,
+    )
+    .bind(cuid1().unwrap())
+    .bind(from)
+    .bind(to)
+    .fetch_one(&mut *tx)
+    .await?;
+
+    publish(tx, &friendship, ServerEvent::RelationshipAdd).await?;
+    Ok(friendship)
+}
+
+/// Turns a pending request into a friendship.
+pub async fn accept_request(
+    tx: &mut PgConnection,
+    friendship: &mut Friendship,
+) -> Result<(), sqlx::Error> {
+    let accepted_at: DateTime<Utc> = sqlx::query_scalar(
+        r#"UPDATE "friends" SET "accepted_at" = now() WHERE "id" = $1 RETURNING "accepted_at""#,
+    )
+    .bind(&friendship.id)
+    .fetch_one(&mut *tx)
+    .await?;
+
+    friendship.accepted_at = Some(accepted_at);
+    publish(tx, friendship, ServerEvent::RelationshipUpdate).await
+}
+
+/// Ends a friendship, or drops a request whichever side it's pending on.
+pub async fn remove_friendship(
+    tx: &mut PgConnection,
+    friendship: &Friendship,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(r#"DELETE FROM "friends" WHERE "id" = $1"#)
+        .bind(&friendship.id)
+        .execute(&mut *tx)
+        .await?;
+
+    publish(tx, friendship, ServerEvent::RelationshipRemove).await
+}
@@ -0,0 +1,138 @@
+use serde::Serialize;
+use sqlx::PgConnection;
+
+/// What anyone may see of a user.
+#[derive(sqlx::FromRow, Serialize)]
+pub struct PublicUser {
+    pub id: String,
+    pub username: String,
+    pub display_name: String,
+}
+
+/// A user as `viewer` sees them on their profile.
+#[derive(Serialize)]
+pub struct Profile {
+    #[serde(flatten)]
+    pub user: PublicUser,
+    /// Friends the two have in common.
+    pub mutual_friends: Vec<PublicUser>,
+    /// Ids of the channels both are members of.
+    pub mutual_channels: Vec<String>,
+}
+
+/// Accepted friends of `$1`, as a subquery.
+const FRIENDS_OF: &str = r#"SELECT CASE WHEN "user_a" = $1 THEN "user_b" ELSE "user_a" END
+  FROM "friends"
+  WHERE "accepted_at" IS NOT NULL AND ("user_a" = $1 OR "user_b" = $1)"#;
+
+/// Whether `$1` and `"u"."id"` blocked one another, either way.
+const BLOCKED: &str = r#"EXISTS (
+  SELECT FROM "user_blocks"
+  WHERE ("blocker_id" = $1 AND "blocked_id" = "u"."id")
+    OR ("blocker_id" = "u"."id" AND "blocked_id" = $1)
+)"#;
+
+/// Users whose username or display name starts with `query` or resembles
+/// it, best matches first. Leaves out `viewer`, anyone who blocked them or
+/// whom they blocked, and users who don't want to be found unless they are
+/// friends already. `query` is expected lowercase.
+pub async fn search_users(
+    conn: &mut PgConnection,
+    viewer: &str,
+    query: &str,
+    limit: i64,
+) -> Result<Vec<PublicUser>, sqlx::Error> {
+    let prefix = format!(
+        "{}%",
+        query
+            .replace('\\', "\\\\")
+            .replace('%', "\\%")
+            .replace('_', "\\_")
+    );
+
+    sqlx::query_as::<_, PublicUser>(&format!(
+        r#"SELECT "u"."id", "u"."username", "u"."display_name"
+        FROM "users" AS "u"
+        WHERE "u"."id" != $1
+          AND (
+            lower("u"."username") LIKE $3
+            OR lower("u"."display_name") LIKE $3
+            OR lower("u"."username") % $2
+            OR lower("u"."display_name") % $2
+          )
+          AND ("u"."discoverable" OR "u"."id" IN ({friends}))
+          AND NOT {blocked}
+        ORDER BY
+          lower("u"."username") = $2 DESC,
+          lower("u"."username") LIKE $3 DESC,
+          GREATEST(
+            similarity(lower("u"."username"), $2),
+            similarity(lower("u"."display_name"), $2)
+          ) DESC,
+          "u"."username"
+        LIMIT $4"#,
+        friends = FRIENDS_OF,
+        blocked = BLOCKED,
+    ))
+    .bind(viewer)
+    .bind(query)
+    .bind(prefix)
+    .bind(limit)
+    .fetch_all(conn)
+    .await
+}
+
+/// The profile of `user_id` as `viewer` sees it, unless either blocked the
+/// other.
+pub async fn get_profile(
+    conn: &mut PgConnection,
+    viewer: &str,
+    user_id: &str,
+) -> Result<Option<Profile>, sqlx::Error> {
+    let user = sqlx::query_as::<_, PublicUser>(&format!(
+        r#"SELECT "u"."id", "u"."username", "u"."display_name"
+        FROM "users" AS "u"
+        WHERE "u"."id" = $2 AND NOT {}"#,
+        BLOCKED
+    ))
+    .bind(viewer)
+    .bind(user_id)
+    .fetch_optional(&mut *conn)
+    .await?;
+
+    let Some(user) = user else {
+        return Ok(None);
+    };
+
+    let mutual_friends = sqlx::query_as::<_, PublicUser>(&format!(
+        r#"SELECT "u"."id", "u"."username", "u"."display_name"
+        FROM "users" AS "u"
+        WHERE "u"."id" IN ({friends})
+          AND "u"."id" IN ({theirs})
+        ORDER BY "u"."username""#,
+        friends = FRIENDS_OF,
+        theirs = FRIENDS_OF.replace("$1", "$2"),
+    ))
+    .bind(viewer)
+    .bind(user_id)
+    .fetch_all(&mut *conn)
+    .await?;
+
+    let mutual_channels: Vec<String> = sqlx::query_scalar(
+        r#"SELECT "a"."channel_id"
+        FROM "channel_members" AS "a"
+          JOIN "channel_members" AS "b" ON "b"."channel_id" = "a"."channel_id"
+        WHERE "a"."user_id" = $1 AND "b"."user_id" = $2
+        ORDER BY "a"."channel_id""#,
+    )
+    .bind(viewer)
+    .bind(user_id)
+    .fetch_all(&mut *conn)
+    .await?;
+
+    Ok(Some(Profile {
+        user,
+        mutual_friends,
+        mutual_channels,
+    }))
+}
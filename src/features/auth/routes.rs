@@ -65,9 +65,9 @@ async fn login(body: Bytes) -> impl IntoResponse {
     let user = user_res.unwrap();
     let hash = argon2::PasswordHash::new(&user.password_hash).unwrap();
 
-    if !Argon2::default()
-        .verify_password(&payload.password.as_bytes(), &hash)
-        .is_ok()
+    if Argon2::default()
+        .verify_password(payload.password.as_bytes(), &hash)
+        .is_err()
     {
         return Json(json!({ "ok": 0, "error": "Invalid Credentials", })).into_response();
     }
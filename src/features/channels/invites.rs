@@ -0,0 +1,116 @@
+use axum::{
+    body::Bytes,
+    extract::Path,
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use chrono::{Duration, Utc};
+use cuid::cuid2_slug;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{
+    database::sql::{
+        channel_invites::{get_channel_invites, Invite},
+        channel_members::get_channel,
+    },
+    middlewares::auth::AuthUser,
+    utils::{
+        channel_cache_keys, decompress::decode_zlib_json, error_response, failure_response,
+        respond, Failure,
+    },
+    DB_POOL,
+};
+
+use super::{
+    permissions::Permissions,
+    routes::{authorize, lock_group, member_group, unarchived},
+};
+
+pub const MAX_INVITE_USES: i32 = 100;
+
+/// Longest an invite can stay valid, in seconds. Invites without `max_age`
+/// never expire.
+pub const MAX_INVITE_AGE: i64 = 30 * 24 * 60 * 60;
+
+#[derive(Deserialize)]
+struct CreateInvitePayload {
+    max_uses: Option<i32>,
+    /// Seconds until the invite expires.
+    max_age: Option<i64>,
+}
+
+async fn list_invites(auth_user: AuthUser, Path(id): Path<String>) -> Response {
+    let pool = DB_POOL.get().unwrap();
+
+    let res = async {
+        let mut conn = pool.acquire().await?;
+        let channel = member_group(get_channel(&mut conn, &id).await?, &auth_user.me)?;
+        authorize(&mut conn, &channel, &auth_user.me, Permissions::ADD_MEMBERS).await?;
+
+        let invites = get_channel_invites(&mut conn, &channel.id).await?;
+        Ok::<_, Failure>(invites)
+    }
+    .await;
+
+    match res {
+        Ok(invites) => Json(json!({ "ok": 1, "invites": invites })).into_response(),
+        Err(failure) => failure_response("Invites_LI", failure),
+    }
+}
+
+async fn create_invite(auth_user: AuthUser, Path(id): Path<String>, body: Bytes) -> Response {
+    let payload: CreateInvitePayload = match decode_zlib_json(body) {
+        Ok(p) => p,
+        Err(_) => return error_response("Invalid Payload"),
+    };
+
+    if payload
+        .max_uses
+        .is_some_and(|uses| !(1..=MAX_INVITE_USES).contains(&uses))
+    {
+        return error_response("Invites take 1 to 100 uses");
+    }
+    if payload
+        .max_age
+        .is_some_and(|age| !(1..=MAX_INVITE_AGE).contains(&age))
+    {
+        return error_response("Invites last 30 days at most");
+    }
+
+    let pool = DB_POOL.get().unwrap();
+
+    let res = async {
+        let mut tx = pool.begin().await?;
+        let channel = lock_group(&mut tx, &id, &auth_user.me).await?;
+        authorize(&mut tx, &channel, &auth_user.me, Permissions::ADD_MEMBERS).await?;
+        unarchived(&channel)?;
+
+        let expires_at = payload.max_age.map(|age| Utc::now() + Duration::seconds(age));
+
+        let invite = sqlx::query_as::<_, Invite>(
+            r#"INSERT INTO "channel_invites" ("code", "channel_id", "inviter_id", "max_uses", "expires_at")
+            VALUES ($1, $2, $3, $4, $5)
+            RETURNING "code", "channel_id", "inviter_id", "max_uses", "uses", "expires_at", "created_at""#,
+        )
+        .bind(cuid2_slug())
+        .bind(&channel.id)
+        .bind(&auth_user.me)
+        .bind(payload.max_uses)
+        .bind(expires_at)
+        .fetch_one(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok::<_, Failure>((invite, vec![]))
+    }
+    .await;
+
+    respond("Invites_CI", "invite", res, channel_cache_keys).await
+}
+
+pub fn routes() -> Router {
+    Router::new().route("/{id}/invites", get(list_invites).post(create_invite))
+}
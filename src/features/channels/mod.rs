@@ -0,0 +1,7 @@
+pub mod invites;
+pub mod permissions;
+pub mod pins;
+pub mod reads;
+pub mod roles;
+pub mod routes;
+pub mod settings;
\ No newline at end of file
@@ -0,0 +1,176 @@
+use bitflags::bitflags;
+use sqlx::PgConnection;
+
+use crate::{database::sql::create_channel::DM, features::realtime::events::Channel};
+
+bitflags! {
+    /// What a member may do in a channel. Stored as `BIGINT`, so a bit keeps
+    /// its meaning once assigned.
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub struct Permissions: i64 {
+        const SEND_MESSAGES = 1 << 0;
+        const ADD_MEMBERS = 1 << 1;
+        const REMOVE_MEMBERS = 1 << 2;
+        const PIN_MESSAGES = 1 << 3;
+        /// Delete messages written by others.
+        const DELETE_MESSAGES = 1 << 4;
+        /// Rename the channel and change its icon and topic.
+        const MANAGE_CHANNEL = 1 << 5;
+        /// Create, edit and assign roles, and change the default permissions.
+        const MANAGE_ROLES = 1 << 6;
+    }
+}
+
+impl Permissions {
+    /// What both sides of a DM may do, as DMs have neither owner nor roles.
+    pub const DM: Self = Self::SEND_MESSAGES.union(Self::PIN_MESSAGES);
+}
+
+/// What `uid` may do in `channel` regardless of roles, which is the case in
+/// DMs and for the owner.
+fn without_roles(channel: &Channel, uid: &str) -> Option<Permissions> {
+    if channel.kind == DM {
+        return Some(Permissions::DM);
+    }
+    if channel.owner_id.as_deref() == Some(uid) {
+        return Some(Permissions::all());
+    }
+    None
+}
+
+/// What `uid`, a member of `channel`, may do in it: everything for the
+/// owner, otherwise the channel's defaults and whatever their roles add.
+pub async fn resolve(
+    conn: &mut PgConnection,
+    channel: &Channel,
+    uid: &str,
+) -> Result<Permissions, sqlx::Error> {
+    if let Some(permissions) = without_roles(channel, uid) {
+        return Ok(permissions);
+    }
+
+    let granted: i64 = sqlx::query_scalar(
+        r#"SELECT COALESCE(bit_or("r"."permissions"), 0)
+        FROM "channel_member_roles" AS "mr"
+          JOIN "channel_roles" AS "r" ON "r"."id" = "mr"."role_id"
+        WHERE "mr"."channel_id" = $1 AND "mr"."user_id" = $2"#,
+    )
+    .bind(&channel.id)
+    .bind(uid)
+    .fetch_one(conn)
+    .await?;
+
+    Ok(Permissions::from_bits_truncate(
+        channel.default_permissions | granted,
+    ))
+}
+
+/// Whether `uid` holds every permission in `needed` for `channel`. Anything
+/// acting on a channel on a member's behalf goes through here.
+pub async fn check(
+    conn: &mut PgConnection,
+    channel: &Channel,
+    uid: &str,
+    needed: Permissions,
+) -> Result<bool, sqlx::Error> {
+    Ok(resolve(conn, channel, uid).await?.contains(needed))
+}
+
+/// The permissions `bits` stand for, as long as `held` covers all of them.
+pub fn grantable(held: Permissions, bits: i64) -> Result<Permissions, &'static str> {
+    let Some(requested) = Permissions::from_bits(bits) else {
+        return Err("Unknown Permissions");
+    };
+
+    if !held.contains(requested) {
+        return Err("You can't grant permissions you don't have");
+    }
+
+    Ok(requested)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::*;
+
+    fn channel(kind: &str, owner_id: Option<&str>) -> Channel {
+        Channel {
+            id: "ch".to_owned(),
+            name: None,
+            kind: kind.to_owned(),
+            owner_id: owner_id.map(str::to_owned),
+            default_permissions: Permissions::SEND_MESSAGES.bits(),
+            topic: None,
+            icon: None,
+            archived_at: None,
+            max_pins: 50,
+            created_at: Utc::now(),
+            members: vec!["a".to_owned(), "b".to_owned()],
+            settings: None,
+        }
+    }
+
+    #[test]
+    fn dms_grant_the_same_to_both_sides() {
+        let dm = channel(DM, None);
+
+        assert_eq!(without_roles(&dm, "a"), Some(Permissions::DM));
+        assert_eq!(without_roles(&dm, "b"), Some(Permissions::DM));
+        assert!(!Permissions::DM.contains(Permissions::MANAGE_ROLES));
+    }
+
+    #[test]
+    fn owner_gets_everything() {
+        let group = channel("GROUP", Some("a"));
+
+        assert_eq!(without_roles(&group, "a"), Some(Permissions::all()));
+        assert_eq!(without_roles(&group, "b"), None);
+    }
+
+    #[test]
+    fn ownerless_group_goes_by_roles() {
+        let group = channel("GROUP", None);
+
+        assert_eq!(without_roles(&group, "a"), None);
+    }
+
+    #[test]
+    fn grants_what_the_granter_holds() {
+        let held = Permissions::SEND_MESSAGES | Permissions::PIN_MESSAGES;
+
+        assert_eq!(
+            grantable(held, Permissions::PIN_MESSAGES.bits()),
+            Ok(Permissions::PIN_MESSAGES)
+        );
+        assert_eq!(grantable(held, 0), Ok(Permissions::empty()));
+        assert_eq!(
+            grantable(Permissions::all(), Permissions::all().bits()),
+            Ok(Permissions::all())
+        );
+    }
+
+    #[test]
+    fn refuses_more_than_the_granter_holds() {
+        let held = Permissions::SEND_MESSAGES | Permissions::MANAGE_ROLES;
+        let bits = (Permissions::MANAGE_ROLES | Permissions::REMOVE_MEMBERS).bits();
+
+        assert_eq!(
+            grantable(held, bits),
+            Err("You can't grant permissions you don't have")
+        );
+    }
+
+    #[test]
+    fn refuses_unknown_bits() {
+        assert_eq!(
+            grantable(Permissions::all(), 1 << 40),
+            Err("Unknown Permissions")
+        );
+        assert_eq!(
+            grantable(Permissions::all(), -1),
+            Err("Unknown Permissions")
+        );
+    }
+}
@@ -0,0 +1,137 @@
+use axum::{
+    extract::Path,
+    response::{IntoResponse, Response},
+    routing::{get, put},
+    Json, Router,
+};
+use serde_json::json;
+
+use crate::{
+    database::sql::{
+        channel_members::{get_channel, lock_channel},
+        channel_pins::{get_pinned_messages, publish_pins},
+    },
+    middlewares::auth::AuthUser,
+    utils::{channel_cache_keys, failure_response, respond, Failure},
+    DB_POOL,
+};
+
+use super::{
+    permissions::Permissions,
+    routes::{authorize, member_channel, unarchived},
+};
+
+async fn list_pins(auth_user: AuthUser, Path(id): Path<String>) -> Response {
+    let pool = DB_POOL.get().unwrap();
+
+    let res = async {
+        let mut conn = pool.acquire().await?;
+        let channel = member_channel(get_channel(&mut conn, &id).await?, &auth_user.me)?;
+
+        let pins = get_pinned_messages(&mut conn, &channel.id).await?;
+        Ok::<_, Failure>(pins)
+    }
+    .await;
+
+    match res {
+        Ok(pins) => Json(json!({ "ok": 1, "pins": pins })).into_response(),
+        Err(failure) => failure_response("Pins_LP", failure),
+    }
+}
+
+/// Pins a message of the channel, up to the channel's `max_pins`. Pinning
+/// one that already is changes nothing.
+async fn pin_message(
+    auth_user: AuthUser,
+    Path((id, message_id)): Path<(String, String)>,
+) -> Response {
+    let pool = DB_POOL.get().unwrap();
+
+    let res = async {
+        let mut tx = pool.begin().await?;
+        let channel = member_channel(lock_channel(&mut tx, &id).await?, &auth_user.me)?;
+        authorize(&mut tx, &channel, &auth_user.me, Permissions::PIN_MESSAGES).await?;
+        unarchived(&channel)?;
+
+        let kind: Option<String> = sqlx::query_scalar(
+            r#"SELECT "type" FROM "messages" WHERE "id" = $1 AND "channel_id" = $2"#,
+        )
+        .bind(&message_id)
+        .bind(&channel.id)
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        match kind.as_deref() {
+            Some("DEFAULT") => {}
+            Some(_) => return Err(Failure::Rejected("System messages can't be pinned")),
+            None => return Err(Failure::Rejected("Unknown Message")),
+        }
+
+        let (pinned, count): (bool, i64) = sqlx::query_as(
+            r#"SELECT COALESCE(bool_or("message_id" = $2), false), COUNT(*) FROM "channel_pins"
+            WHERE "channel_id" = $1"#,
+        )
+        .bind(&channel.id)
+        .bind(&message_id)
+        .fetch_one(&mut *tx)
+        .await?;
+
+        if !pinned && count >= i64::from(channel.max_pins) {
+            return Err(Failure::Rejected("Too many pins"));
+        }
+
+        sqlx::query(
+            r#"INSERT INTO "channel_pins" ("channel_id", "message_id", "pinned_by")
+            VALUES ($1, $2, $3)
+            ON CONFLICT DO NOTHING"#,
+        )
+        .bind(&channel.id)
+        .bind(&message_id)
+        .bind(&auth_user.me)
+        .execute(&mut *tx)
+        .await?;
+
+        let pins = publish_pins(&mut tx, &channel.id).await?;
+        tx.commit().await?;
+
+        Ok((pins, vec![]))
+    }
+    .await;
+
+    respond("Pins_PM", "pins", res, channel_cache_keys).await
+}
+
+async fn unpin_message(
+    auth_user: AuthUser,
+    Path((id, message_id)): Path<(String, String)>,
+) -> Response {
+    let pool = DB_POOL.get().unwrap();
+
+    let res = async {
+        let mut tx = pool.begin().await?;
+        let channel = member_channel(lock_channel(&mut tx, &id).await?, &auth_user.me)?;
+        authorize(&mut tx, &channel, &auth_user.me, Permissions::PIN_MESSAGES).await?;
+        unarchived(&channel)?;
+
+        sqlx::query(r#"DELETE FROM "channel_pins" WHERE "channel_id" = $1 AND "message_id" = $2"#)
+            .bind(&channel.id)
+            .bind(&message_id)
+            .execute(&mut *tx)
+            .await?;
+
+        let pins = publish_pins(&mut tx, &channel.id).await?;
+        tx.commit().await?;
+
+        Ok((pins, vec![]))
+    }
+    .await;
+
+    respond("Pins_UP", "pins", res, channel_cache_keys).await
+}
+
+pub fn routes() -> Router {
+    Router::new().route("/{id}/pins", get(list_pins)).route(
+        "/{id}/pins/{message_id}",
+        put(pin_message).delete(unpin_message),
+    )
+}
@@ -0,0 +1,71 @@
+use axum::{extract::Path, response::Response, routing::post, Router};
+use serde_json::json;
+
+use crate::{
+    database::sql::{
+        channel_members::get_channel,
+        message_reads::{get_read_marker, mark_read},
+        privacy::get_privacy,
+    },
+    middlewares::auth::AuthUser,
+    utils::{channel_cache_keys, error_response, respond, Failure},
+    DB_POOL, RD_POOL,
+};
+
+use super::routes::member_channel;
+
+/// Marks a channel read up to a message. Other members only hear about it
+/// if the caller sends read receipts.
+async fn ack_message(
+    auth_user: AuthUser,
+    Path((id, message_id)): Path<(String, String)>,
+) -> Response {
+    let Ok(mut conn) = RD_POOL
+        .get()
+        .unwrap()
+        .get_multiplexed_async_connection()
+        .await
+    else {
+        return error_response("Internal Server Error");
+    };
+    let share = get_privacy(&mut conn, &auth_user.me).await.read_receipts;
+
+    let pool = DB_POOL.get().unwrap();
+
+    let res = async {
+        let mut tx = pool.begin().await?;
+        let channel = member_channel(get_channel(&mut tx, &id).await?, &auth_user.me)?;
+
+        let exists: bool = sqlx::query_scalar(
+            r#"SELECT EXISTS (SELECT FROM "messages" WHERE "id" = $1 AND "channel_id" = $2)"#,
+        )
+        .bind(&message_id)
+        .bind(&channel.id)
+        .fetch_one(&mut *tx)
+        .await?;
+        if !exists {
+            return Err(Failure::Rejected("Unknown Message"));
+        }
+
+        let ack = match mark_read(&mut tx, &channel.id, &auth_user.me, &message_id, share).await? {
+            Some(read_at) => json!({ "message_id": message_id, "read_at": read_at }),
+            // Already read past it, so the marker stays where it is.
+            None => {
+                let (message_id, read_at) = get_read_marker(&mut tx, &channel.id, &auth_user.me)
+                    .await?
+                    .unwrap_or_default();
+                json!({ "message_id": message_id, "read_at": read_at })
+            }
+        };
+        tx.commit().await?;
+
+        Ok((ack, vec![auth_user.me.clone()]))
+    }
+    .await;
+
+    respond("Reads_AM", "ack", res, channel_cache_keys).await
+}
+
+pub fn routes() -> Router {
+    Router::new().route("/{id}/messages/{message_id}/ack", post(ack_message))
+}
@@ -0,0 +1,343 @@
+use axum::{
+    body::Bytes,
+    extract::Path,
+    response::{IntoResponse, Response},
+    routing::{get, patch, put},
+    Json, Router,
+};
+use cuid::cuid1;
+use serde::Deserialize;
+use serde_json::json;
+use sqlx::PgConnection;
+
+use crate::{
+    database::sql::{
+        channel_members::get_channel,
+        channel_roles::{self, publish_roles},
+    },
+    features::realtime::events::Channel,
+    middlewares::auth::AuthUser,
+    utils::{
+        channel_cache_keys, decompress::decode_zlib_json, error_response, failure_response,
+        respond, Failure,
+    },
+    DB_POOL,
+};
+
+use super::{
+    permissions::{self, Permissions},
+    routes::{authorize, lock_group, member_group},
+};
+
+/// Most roles a group can have.
+pub const MAX_ROLES: i64 = 20;
+
+pub const MAX_ROLE_NAME_LENGTH: usize = 32;
+
+#[derive(Deserialize)]
+struct CreateRolePayload {
+    name: String,
+    permissions: i64,
+}
+
+#[derive(Deserialize)]
+struct UpdateRolePayload {
+    name: Option<String>,
+    permissions: Option<i64>,
+}
+
+#[derive(Deserialize)]
+struct DefaultPermissionsPayload {
+    permissions: i64,
+}
+
+fn role_name(name: &str) -> Result<String, Failure> {
+    let name = name.trim();
+    if name.is_empty() || name.chars().count() > MAX_ROLE_NAME_LENGTH {
+        return Err(Failure::Rejected("Role names take 1 to 32 characters"));
+    }
+    Ok(name.to_owned())
+}
+
+/// Checks that `actor` holds every permission they're handing out, so a role
+/// can't be used to grant anyone, themselves included, more than they have.
+async fn grantable(
+    conn: &mut PgConnection,
+    channel: &Channel,
+    actor: &str,
+    bits: i64,
+) -> Result<Permissions, Failure> {
+    let held = permissions::resolve(conn, channel, actor).await?;
+    permissions::grantable(held, bits).map_err(Failure::Rejected)
+}
+
+/// Checks that `role_id` belongs to `channel` and grants nothing beyond what
+/// `actor` holds, the same bar as for creating it.
+async fn manageable_role(
+    conn: &mut PgConnection,
+    channel: &Channel,
+    actor: &str,
+    role_id: &str,
+) -> Result<(), Failure> {
+    let bits: Option<i64> = sqlx::query_scalar(
+        r#"SELECT "permissions" FROM "channel_roles" WHERE "id" = $1 AND "channel_id" = $2"#,
+    )
+    .bind(role_id)
+    .bind(&channel.id)
+    .fetch_optional(&mut *conn)
+    .await?;
+
+    let Some(bits) = bits else {
+        return Err(Failure::Rejected("Unknown Role"));
+    };
+
+    grantable(conn, channel, actor, bits).await.map(|_| ())
+}
+
+async fn list_roles(auth_user: AuthUser, Path(id): Path<String>) -> Response {
+    let pool = DB_POOL.get().unwrap();
+
+    let res = async {
+        let mut conn = pool.acquire().await?;
+        let channel = member_group(get_channel(&mut conn, &id).await?, &auth_user.me)?;
+
+        let roles = channel_roles::get_roles(&mut conn, &channel.id).await?;
+        Ok::<_, Failure>(roles)
+    }
+    .await;
+
+    match res {
+        Ok(roles) => Json(json!({ "ok": 1, "roles": roles })).into_response(),
+        Err(failure) => failure_response("Roles_LR", failure),
+    }
+}
+
+async fn create_role(auth_user: AuthUser, Path(id): Path<String>, body: Bytes) -> Response {
+    let payload: CreateRolePayload = match decode_zlib_json(body) {
+        Ok(p) => p,
+        Err(_) => return error_response("Invalid Payload"),
+    };
+
+    let pool = DB_POOL.get().unwrap();
+
+    let res = async {
+        let name = role_name(&payload.name)?;
+
+        let mut tx = pool.begin().await?;
+        let channel = lock_group(&mut tx, &id, &auth_user.me).await?;
+        authorize(&mut tx, &channel, &auth_user.me, Permissions::MANAGE_ROLES).await?;
+        let permissions = grantable(&mut tx, &channel, &auth_user.me, payload.permissions).await?;
+
+        let count: i64 =
+            sqlx::query_scalar(r#"SELECT COUNT(*) FROM "channel_roles" WHERE "channel_id" = $1"#)
+                .bind(&channel.id)
+                .fetch_one(&mut *tx)
+                .await?;
+        if count >= MAX_ROLES {
+            return Err(Failure::Rejected("Too many roles"));
+        }
+
+        sqlx::query(
+            r#"INSERT INTO "channel_roles" ("id", "channel_id", "name", "permissions")
+            VALUES ($1, $2, $3, $4)"#,
+        )
+        .bind(cuid1().unwrap())
+        .bind(&channel.id)
+        .bind(name)
+        .bind(permissions.bits())
+        .execute(&mut *tx)
+        .await?;
+
+        let roles = publish_roles(&mut tx, &channel.id).await?;
+        tx.commit().await?;
+
+        Ok((roles, vec![]))
+    }
+    .await;
+
+    respond("Roles_CR", "roles", res, channel_cache_keys).await
+}
+
+async fn update_role(
+    auth_user: AuthUser,
+    Path((id, role_id)): Path<(String, String)>,
+    body: Bytes,
+) -> Response {
+    let payload: UpdateRolePayload = match decode_zlib_json(body) {
+        Ok(p) => p,
+        Err(_) => return error_response("Invalid Payload"),
+    };
+
+    let pool = DB_POOL.get().unwrap();
+
+    let res = async {
+        let name = payload.name.as_deref().map(role_name).transpose()?;
+
+        let mut tx = pool.begin().await?;
+        let channel = lock_group(&mut tx, &id, &auth_user.me).await?;
+        authorize(&mut tx, &channel, &auth_user.me, Permissions::MANAGE_ROLES).await?;
+        manageable_role(&mut tx, &channel, &auth_user.me, &role_id).await?;
+
+        let permissions = match payload.permissions {
+            Some(bits) => Some(grantable(&mut tx, &channel, &auth_user.me, bits).await?),
+            None => None,
+        };
+
+        sqlx::query(
+            r#"UPDATE "channel_roles"
+            SET "name" = COALESCE($2, "name"), "permissions" = COALESCE($3, "permissions")
+            WHERE "id" = $1"#,
+        )
+        .bind(&role_id)
+        .bind(name)
+        .bind(permissions.map(|permissions| permissions.bits()))
+        .execute(&mut *tx)
+        .await?;
+
+        let roles = publish_roles(&mut tx, &channel.id).await?;
+        tx.commit().await?;
+
+        Ok((roles, vec![]))
+    }
+    .await;
+
+    respond("Roles_UR", "roles", res, channel_cache_keys).await
+}
+
+async fn delete_role(auth_user: AuthUser, Path((id, role_id)): Path<(String, String)>) -> Response {
+    let pool = DB_POOL.get().unwrap();
+
+    let res = async {
+        let mut tx = pool.begin().await?;
+        let channel = lock_group(&mut tx, &id, &auth_user.me).await?;
+        authorize(&mut tx, &channel, &auth_user.me, Permissions::MANAGE_ROLES).await?;
+        manageable_role(&mut tx, &channel, &auth_user.me, &role_id).await?;
+
+        sqlx::query(r#"DELETE FROM "channel_roles" WHERE "id" = $1"#)
+            .bind(&role_id)
+            .execute(&mut *tx)
+            .await?;
+
+        let roles = publish_roles(&mut tx, &channel.id).await?;
+        tx.commit().await?;
+
+        Ok((roles, vec![]))
+    }
+    .await;
+
+    respond("Roles_DR", "roles", res, channel_cache_keys).await
+}
+
+async fn assign_role(
+    auth_user: AuthUser,
+    Path((id, user_id, role_id)): Path<(String, String, String)>,
+) -> Response {
+    let pool = DB_POOL.get().unwrap();
+
+    let res = async {
+        let mut tx = pool.begin().await?;
+        let channel = lock_group(&mut tx, &id, &auth_user.me).await?;
+        authorize(&mut tx, &channel, &auth_user.me, Permissions::MANAGE_ROLES).await?;
+        manageable_role(&mut tx, &channel, &auth_user.me, &role_id).await?;
+
+        if !channel.members.contains(&user_id) {
+            return Err(Failure::Rejected("Not a member"));
+        }
+
+        sqlx::query(
+            r#"INSERT INTO "channel_member_roles" ("channel_id", "user_id", "role_id")
+            VALUES ($1, $2, $3)
+            ON CONFLICT DO NOTHING"#,
+        )
+        .bind(&channel.id)
+        .bind(&user_id)
+        .bind(&role_id)
+        .execute(&mut *tx)
+        .await?;
+
+        let roles = publish_roles(&mut tx, &channel.id).await?;
+        tx.commit().await?;
+
+        Ok((roles, vec![]))
+    }
+    .await;
+
+    respond("Roles_AR", "roles", res, channel_cache_keys).await
+}
+
+async fn unassign_role(
+    auth_user: AuthUser,
+    Path((id, user_id, role_id)): Path<(String, String, String)>,
+) -> Response {
+    let pool = DB_POOL.get().unwrap();
+
+    let res = async {
+        let mut tx = pool.begin().await?;
+        let channel = lock_group(&mut tx, &id, &auth_user.me).await?;
+        authorize(&mut tx, &channel, &auth_user.me, Permissions::MANAGE_ROLES).await?;
+        manageable_role(&mut tx, &channel, &auth_user.me, &role_id).await?;
+
+        sqlx::query(
+            r#"DELETE FROM "channel_member_roles"
+            WHERE "channel_id" = $1 AND "user_id" = $2 AND "role_id" = $3"#,
+        )
+        .bind(&channel.id)
+        .bind(&user_id)
+        .bind(&role_id)
+        .execute(&mut *tx)
+        .await?;
+
+        let roles = publish_roles(&mut tx, &channel.id).await?;
+        tx.commit().await?;
+
+        Ok((roles, vec![]))
+    }
+    .await;
+
+    respond("Roles_UA", "roles", res, channel_cache_keys).await
+}
+
+/// Changes what members may do without any role.
+async fn update_default_permissions(
+    auth_user: AuthUser,
+    Path(id): Path<String>,
+    body: Bytes,
+) -> Response {
+    let payload: DefaultPermissionsPayload = match decode_zlib_json(body) {
+        Ok(p) => p,
+        Err(_) => return error_response("Invalid Payload"),
+    };
+
+    let pool = DB_POOL.get().unwrap();
+
+    let res = async {
+        let mut tx = pool.begin().await?;
+        let mut channel = lock_group(&mut tx, &id, &auth_user.me).await?;
+        authorize(&mut tx, &channel, &auth_user.me, Permissions::MANAGE_ROLES).await?;
+        let permissions = grantable(&mut tx, &channel, &auth_user.me, payload.permissions).await?;
+
+        channel_roles::update_default_permissions(&mut tx, &mut channel, permissions.bits())
+            .await?;
+        tx.commit().await?;
+
+        let affected = channel.members.clone();
+        Ok((channel, affected))
+    }
+    .await;
+
+    respond("Roles_UDP", "channel", res, channel_cache_keys).await
+}
+
+pub fn routes() -> Router {
+    Router::new()
+        .route("/{id}/roles", get(list_roles).post(create_role))
+        .route(
+            "/{id}/roles/{role_id}",
+            patch(update_role).delete(delete_role),
+        )
+        .route(
+            "/{id}/members/{user_id}/roles/{role_id}",
+            put(assign_role).delete(unassign_role),
+        )
+        .route("/{id}/permissions", patch(update_default_permissions))
+}
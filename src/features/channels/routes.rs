@@ -0,0 +1,304 @@
+use axum::{
+    body::Bytes,
+    extract::Path,
+    response::{IntoResponse, Response},
+    routing::{post, put},
+    Json, Router,
+};
+use serde::Deserialize;
+use serde_json::json;
+use sqlx::{PgConnection, PgExecutor};
+
+use crate::{
+    database::sql::{
+        channel_members,
+        create_channel::{create_channel, GROUP},
+    },
+    features::realtime::events::Channel,
+    middlewares::auth::AuthUser,
+    utils::{
+        channel_cache_keys, decompress::decode_zlib_json, error_response, invalidate_cache,
+        log_error, respond, Failure,
+    },
+    DB_POOL,
+};
+
+use super::{
+    invites,
+    permissions::{self, Permissions},
+    pins, reads, roles, settings,
+};
+
+/// Most people a group can hold, its creator included.
+pub const MAX_GROUP_MEMBERS: usize = 10;
+
+pub const MAX_NAME_LENGTH: usize = 100;
+
+#[derive(Deserialize)]
+struct CreateGroupPayload {
+    name: Option<String>,
+    members: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct TransferOwnershipPayload {
+    user_id: String,
+}
+
+/// How many of `ids` are accepted friends of `uid`.
+async fn count_friends<'c>(
+    executor: impl PgExecutor<'c>,
+    uid: &str,
+    ids: &[String],
+) -> Result<usize, sqlx::Error> {
+    let friends: i64 = sqlx::query_scalar(
+        r#"SELECT COUNT(DISTINCT CASE WHEN "user_a" = $1 THEN "user_b" ELSE "user_a" END)
+        FROM "friends"
+        WHERE "accepted_at" IS NOT NULL
+          AND (
+            ("user_a" = $1 AND "user_b" = ANY($2))
+            OR ("user_b" = $1 AND "user_a" = ANY($2))
+          )"#,
+    )
+    .bind(uid)
+    .bind(ids)
+    .fetch_one(executor)
+    .await?;
+
+    Ok(friends as usize)
+}
+
+/// Passes a channel on if `uid` is a member of it, DM or group.
+pub(crate) fn member_channel(channel: Option<Channel>, uid: &str) -> Result<Channel, Failure> {
+    match channel {
+        Some(channel) if channel.members.iter().any(|member| member == uid) => Ok(channel),
+        _ => Err(Failure::Rejected("Unknown Channel")),
+    }
+}
+
+/// Passes a channel on if it's a group `uid` is a member of.
+pub(crate) fn member_group(channel: Option<Channel>, uid: &str) -> Result<Channel, Failure> {
+    let channel = member_channel(channel, uid)?;
+    if channel.kind != GROUP {
+        return Err(Failure::Rejected("Only groups can be managed"));
+    }
+    Ok(channel)
+}
+
+/// Locks a group `uid` is a member of, for changing it.
+pub(crate) async fn lock_group(
+    tx: &mut PgConnection,
+    id: &str,
+    uid: &str,
+) -> Result<Channel, Failure> {
+    member_group(channel_members::lock_channel(tx, id).await?, uid)
+}
+
+/// Rejects changes to a group that was archived, new members included.
+pub(crate) fn unarchived(channel: &Channel) -> Result<(), Failure> {
+    if channel.archived_at.is_some() {
+        return Err(Failure::Rejected("This group is archived"));
+    }
+    Ok(())
+}
+
+/// Rejects the request unless `uid` holds `needed` in `channel`.
+pub(crate) async fn authorize(
+    conn: &mut PgConnection,
+    channel: &Channel,
+    uid: &str,
+    needed: Permissions,
+) -> Result<(), Failure> {
+    if !permissions::check(conn, channel, uid, needed).await? {
+        return Err(Failure::Rejected("Missing Permissions"));
+    }
+    Ok(())
+}
+
+async fn create_group(auth_user: AuthUser, body: Bytes) -> Response {
+    let payload: CreateGroupPayload = match decode_zlib_json(body) {
+        Ok(p) => p,
+        Err(_) => return error_response("Invalid Payload"),
+    };
+
+    let name = payload
+        .name
+        .as_deref()
+        .map(str::trim)
+        .filter(|name| !name.is_empty());
+    if name.is_some_and(|name| name.chars().count() > MAX_NAME_LENGTH) {
+        return error_response("Name is too long");
+    }
+
+    let mut members = vec![auth_user.me.clone()];
+    for id in payload.members {
+        if !members.contains(&id) {
+            members.push(id);
+        }
+    }
+
+    if members.len() < 2 {
+        return error_response("A group needs at least one other member");
+    }
+    if members.len() > MAX_GROUP_MEMBERS {
+        return error_response("Too many members");
+    }
+
+    let pool = DB_POOL.get().unwrap();
+
+    match count_friends(pool, &auth_user.me, &members[1..]).await {
+        Ok(friends) if friends == members.len() - 1 => {}
+        Ok(_) => return error_response("Groups can only include friends"),
+        Err(err) => {
+            log_error("Channels_CG_FR", Some(&err));
+            return error_response("Internal Server Error");
+        }
+    }
+
+    let channel_res = async {
+        let mut tx = pool.begin().await?;
+        let channel = create_channel(&mut tx, GROUP, name, Some(&auth_user.me), &members).await?;
+        tx.commit().await?;
+        Ok::<_, sqlx::Error>(channel)
+    }
+    .await;
+
+    let channel = match channel_res {
+        Ok(channel) => channel,
+        Err(err) => {
+            log_error("Channels_CG_CC", Some(&err));
+            return error_response("Internal Server Error");
+        }
+    };
+
+    invalidate_cache(&channel_cache_keys(&members)).await;
+
+    Json(json!({ "ok": 1, "channel": channel })).into_response()
+}
+
+/// Adds a friend of the caller to a group they are in.
+async fn add_member(auth_user: AuthUser, Path((id, user_id)): Path<(String, String)>) -> Response {
+    let pool = DB_POOL.get().unwrap();
+
+    let res = async {
+        let mut tx = pool.begin().await?;
+        let mut channel = lock_group(&mut tx, &id, &auth_user.me).await?;
+        authorize(&mut tx, &channel, &auth_user.me, Permissions::ADD_MEMBERS).await?;
+        unarchived(&channel)?;
+
+        if channel.members.contains(&user_id) {
+            return Err(Failure::Rejected("Already a member"));
+        }
+        if channel.members.len() >= MAX_GROUP_MEMBERS {
+            return Err(Failure::Rejected("The group is full"));
+        }
+        if count_friends(&mut *tx, &auth_user.me, std::slice::from_ref(&user_id)).await? != 1 {
+            return Err(Failure::Rejected("Groups can only include friends"));
+        }
+
+        channel_members::add_member(&mut tx, &mut channel, &auth_user.me, &user_id).await?;
+        tx.commit().await?;
+
+        let affected = channel.members.clone();
+        Ok((channel, affected))
+    }
+    .await;
+
+    respond("Channels_AM", "channel", res, channel_cache_keys).await
+}
+
+/// Removes a member other than the owner, or leaves the group when
+/// `user_id` is the caller or `@me`.
+async fn remove_member(
+    auth_user: AuthUser,
+    Path((id, user_id)): Path<(String, String)>,
+) -> Response {
+    let user_id = if user_id == "@me" {
+        auth_user.me.clone()
+    } else {
+        user_id
+    };
+
+    let pool = DB_POOL.get().unwrap();
+
+    let res = async {
+        let mut tx = pool.begin().await?;
+        let mut channel = lock_group(&mut tx, &id, &auth_user.me).await?;
+
+        if !channel.members.contains(&user_id) {
+            return Err(Failure::Rejected("Not a member"));
+        }
+        if user_id != auth_user.me {
+            authorize(
+                &mut tx,
+                &channel,
+                &auth_user.me,
+                Permissions::REMOVE_MEMBERS,
+            )
+            .await?;
+
+            if channel.owner_id.as_deref() == Some(&user_id) {
+                return Err(Failure::Rejected("The owner can't be removed"));
+            }
+        }
+
+        let affected = channel.members.clone();
+        channel_members::remove_member(&mut tx, &mut channel, &auth_user.me, &user_id).await?;
+        tx.commit().await?;
+
+        Ok((channel, affected))
+    }
+    .await;
+
+    respond("Channels_RM", "channel", res, channel_cache_keys).await
+}
+
+/// Hands the group over to another member.
+async fn transfer_ownership(auth_user: AuthUser, Path(id): Path<String>, body: Bytes) -> Response {
+    let payload: TransferOwnershipPayload = match decode_zlib_json(body) {
+        Ok(p) => p,
+        Err(_) => return error_response("Invalid Payload"),
+    };
+
+    let pool = DB_POOL.get().unwrap();
+
+    let res = async {
+        let mut tx = pool.begin().await?;
+        let mut channel = lock_group(&mut tx, &id, &auth_user.me).await?;
+
+        if channel.owner_id.as_deref() != Some(&auth_user.me) {
+            return Err(Failure::Rejected("Only the owner can transfer ownership"));
+        }
+        if payload.user_id == auth_user.me {
+            return Err(Failure::Rejected("You already own this group"));
+        }
+        if !channel.members.contains(&payload.user_id) {
+            return Err(Failure::Rejected("Not a member"));
+        }
+
+        channel_members::transfer_ownership(&mut tx, &mut channel, &auth_user.me, &payload.user_id)
+            .await?;
+        tx.commit().await?;
+
+        let affected = channel.members.clone();
+        Ok((channel, affected))
+    }
+    .await;
+
+    respond("Channels_TO", "channel", res, channel_cache_keys).await
+}
+
+pub fn routes() -> Router {
+    Router::new()
+        .route("/", post(create_group))
+        .route(
+            "/{id}/members/{user_id}",
+            put(add_member).delete(remove_member),
+        )
+        .route("/{id}/owner", post(transfer_ownership))
+        .merge(roles::routes())
+        .merge(invites::routes())
+        .merge(settings::routes())
+        .merge(pins::routes())
+        .merge(reads::routes())
+}
@@ -0,0 +1,176 @@
+use axum::{body::Bytes, extract::Path, response::Response, routing::patch, Router};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+
+use crate::{
+    database::sql::{
+        channel_members::get_channel,
+        channel_settings::{self, get_member_settings},
+    },
+    features::realtime::events::{MemberSettings, Notify},
+    middlewares::auth::AuthUser,
+    utils::{
+        channel_cache_keys, decompress::decode_zlib_json, error_response, nullable, respond,
+        Failure,
+    },
+    DB_POOL,
+};
+
+use super::{
+    permissions::Permissions,
+    routes::{authorize, lock_group, MAX_NAME_LENGTH},
+};
+
+pub const MAX_TOPIC_LENGTH: usize = 1024;
+
+pub const MAX_ICON_LENGTH: usize = 512;
+
+/// Highest pin cap a group can choose.
+pub const MAX_PINS: i32 = 250;
+
+/// Fields left out stay as they are, and `null` clears them.
+#[derive(Deserialize)]
+struct UpdateChannelPayload {
+    #[serde(default, deserialize_with = "nullable")]
+    name: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    topic: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    icon: Option<Option<String>>,
+    max_pins: Option<i32>,
+    archived: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct MemberSettingsPayload {
+    notify: Option<Notify>,
+    #[serde(default, deserialize_with = "nullable")]
+    muted_until: Option<Option<DateTime<Utc>>>,
+}
+
+/// Trims `value`, treating blank as cleared, and rejects it past `max`
+/// characters.
+fn text_field(
+    value: Option<String>,
+    max: usize,
+    too_long: &'static str,
+) -> Result<Option<String>, Failure> {
+    let value = value
+        .map(|value| value.trim().to_owned())
+        .filter(|value| !value.is_empty());
+    if value
+        .as_ref()
+        .is_some_and(|value| value.chars().count() > max)
+    {
+        return Err(Failure::Rejected(too_long));
+    }
+    Ok(value)
+}
+
+/// Renames a group, changes its topic, icon or pin cap, or archives it. An archived
+/// group keeps its history but can't be changed until it's unarchived.
+async fn update_channel(auth_user: AuthUser, Path(id): Path<String>, body: Bytes) -> Response {
+    let payload: UpdateChannelPayload = match decode_zlib_json(body) {
+        Ok(p) => p,
+        Err(_) => return error_response("Invalid Payload"),
+    };
+
+    let pool = DB_POOL.get().unwrap();
+
+    let res = async {
+        let mut tx = pool.begin().await?;
+        let mut channel = lock_group(&mut tx, &id, &auth_user.me).await?;
+        authorize(
+            &mut tx,
+            &channel,
+            &auth_user.me,
+            Permissions::MANAGE_CHANNEL,
+        )
+        .await?;
+
+        match payload.archived {
+            Some(true) if channel.archived_at.is_none() => channel.archived_at = Some(Utc::now()),
+            Some(false) => channel.archived_at = None,
+            _ => {}
+        }
+
+        let edits = payload.name.is_some()
+            || payload.topic.is_some()
+            || payload.icon.is_some()
+            || payload.max_pins.is_some();
+        if edits && channel.archived_at.is_some() {
+            return Err(Failure::Rejected("This group is archived"));
+        }
+
+        if let Some(name) = payload.name {
+            channel.name = text_field(name, MAX_NAME_LENGTH, "Name is too long")?;
+        }
+        if let Some(topic) = payload.topic {
+            channel.topic = text_field(topic, MAX_TOPIC_LENGTH, "Topic is too long")?;
+        }
+        if let Some(icon) = payload.icon {
+            channel.icon = text_field(icon, MAX_ICON_LENGTH, "Icon is too long")?;
+        }
+        if let Some(max_pins) = payload.max_pins {
+            if !(1..=MAX_PINS).contains(&max_pins) {
+                return Err(Failure::Rejected("Groups take 1 to 250 pins"));
+            }
+            channel.max_pins = max_pins;
+        }
+
+        channel_settings::update_channel(&mut tx, &channel).await?;
+        tx.commit().await?;
+
+        let affected = channel.members.clone();
+        Ok((channel, affected))
+    }
+    .await;
+
+    respond("Channels_UC", "channel", res, channel_cache_keys).await
+}
+
+/// Changes how the caller is notified about a channel, DMs included. Only
+/// their own sessions hear about it.
+async fn update_member_settings(
+    auth_user: AuthUser,
+    Path(id): Path<String>,
+    body: Bytes,
+) -> Response {
+    let payload: MemberSettingsPayload = match decode_zlib_json(body) {
+        Ok(p) => p,
+        Err(_) => return error_response("Invalid Payload"),
+    };
+
+    let pool = DB_POOL.get().unwrap();
+
+    let res = async {
+        let mut tx = pool.begin().await?;
+
+        let Some(mut channel) = get_channel(&mut tx, &id).await? else {
+            return Err(Failure::Rejected("Unknown Channel"));
+        };
+        let Some(current) = get_member_settings(&mut tx, &channel.id, &auth_user.me).await? else {
+            return Err(Failure::Rejected("Unknown Channel"));
+        };
+
+        let settings = MemberSettings {
+            notify: payload.notify.unwrap_or(current.notify),
+            muted_until: payload.muted_until.unwrap_or(current.muted_until),
+        };
+
+        channel_settings::update_member_settings(&mut tx, &mut channel, &auth_user.me, settings)
+            .await?;
+        tx.commit().await?;
+
+        Ok((channel, vec![auth_user.me.clone()]))
+    }
+    .await;
+
+    respond("Channels_UMS", "channel", res, channel_cache_keys).await
+}
+
+pub fn routes() -> Router {
+    Router::new()
+        .route("/{id}", patch(update_channel))
+        .route("/{id}/settings", patch(update_member_settings))
+}
@@ -0,0 +1,144 @@
+use axum::{
+    extract::Path,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use serde_json::json;
+
+use crate::{
+    database::sql::{
+        channel_invites::{get_invite, use_invite},
+        channel_members::{self, get_channel, lock_channel},
+    },
+    features::channels::{
+        permissions::Permissions,
+        routes::{authorize, member_group, unarchived, MAX_GROUP_MEMBERS},
+    },
+    middlewares::auth::AuthUser,
+    utils::{channel_cache_keys, error_response, log_error, respond, Failure},
+    DB_POOL,
+};
+
+/// What an invite leads to, for showing before accepting it. Needs no
+/// account, so links can be previewed anywhere.
+async fn preview_invite(Path(code): Path<String>) -> Response {
+    let pool = DB_POOL.get().unwrap();
+
+    let res = async {
+        let mut conn = pool.acquire().await?;
+
+        let Some(invite) = get_invite(&mut conn, &code).await? else {
+            return Ok(None);
+        };
+        let Some(channel) = get_channel(&mut conn, &invite.channel_id).await? else {
+            return Ok(None);
+        };
+
+        Ok::<_, sqlx::Error>(Some(json!({
+            "code": invite.code,
+            "expires_at": invite.expires_at,
+            "channel": {
+                "id": channel.id,
+                "name": channel.name,
+            },
+            "member_count": channel.members.len(),
+        })))
+    }
+    .await;
+
+    match res {
+        Ok(Some(invite)) => Json(json!({ "ok": 1, "invite": invite })).into_response(),
+        Ok(None) => error_response("Invalid Invite"),
+        Err(err) => {
+            log_error("Invites_PI", Some(&err));
+            error_response("Internal Server Error")
+        }
+    }
+}
+
+/// Joins the caller to the invite's group. Accepting one for a group they're
+/// already in doesn't count as a use.
+async fn accept_invite(auth_user: AuthUser, Path(code): Path<String>) -> Response {
+    let pool = DB_POOL.get().unwrap();
+
+    let res = async {
+        let mut tx = pool.begin().await?;
+
+        let Some(invite) = get_invite(&mut tx, &code).await? else {
+            return Err(Failure::Rejected("Invalid Invite"));
+        };
+        let Some(mut channel) = lock_channel(&mut tx, &invite.channel_id).await? else {
+            return Err(Failure::Rejected("Invalid Invite"));
+        };
+
+        if channel.members.contains(&auth_user.me) {
+            return Ok((channel, vec![]));
+        }
+        unarchived(&channel)?;
+        if channel.members.len() >= MAX_GROUP_MEMBERS {
+            return Err(Failure::Rejected("The group is full"));
+        }
+        if !use_invite(&mut tx, &code).await? {
+            return Err(Failure::Rejected("Invalid Invite"));
+        }
+
+        channel_members::add_member(&mut tx, &mut channel, &auth_user.me, &auth_user.me).await?;
+        tx.commit().await?;
+
+        let affected = channel.members.clone();
+        Ok((channel, affected))
+    }
+    .await;
+
+    respond("Invites_AI", "channel", res, channel_cache_keys).await
+}
+
+/// Stops an invite from being used, which its creator and members who can
+/// manage the group may do.
+async fn revoke_invite(auth_user: AuthUser, Path(code): Path<String>) -> Response {
+    let pool = DB_POOL.get().unwrap();
+
+    let res = async {
+        let mut tx = pool.begin().await?;
+
+        let Some(invite) = get_invite(&mut tx, &code).await? else {
+            return Err(Failure::Rejected("Invalid Invite"));
+        };
+        let channel = member_group(
+            get_channel(&mut tx, &invite.channel_id).await?,
+            &auth_user.me,
+        )?;
+
+        if invite.inviter_id != auth_user.me {
+            authorize(
+                &mut tx,
+                &channel,
+                &auth_user.me,
+                Permissions::MANAGE_CHANNEL,
+            )
+            .await?;
+        }
+
+        sqlx::query(
+            r#"UPDATE "channel_invites" SET "revoked_at" = now()
+            WHERE "code" = $1 AND "revoked_at" IS NULL"#,
+        )
+        .bind(&code)
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok((invite, vec![]))
+    }
+    .await;
+
+    respond("Invites_RI", "invite", res, channel_cache_keys).await
+}
+
+pub fn routes() -> Router {
+    Router::new()
+        .route("/{code}", get(preview_invite).delete(revoke_invite))
+        .route("/{code}/accept", post(accept_invite))
+}
@@ -1,2 +1,6 @@
 pub mod auth;
-pub mod realtime;
\ No newline at end of file
+pub mod channels;
+pub mod invites;
+pub mod realtime;
+pub mod relationships;
+pub mod users;
\ No newline at end of file
@@ -0,0 +1,291 @@
+use std::io::{Read, Write};
+
+use axum::extract::ws::Message;
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use serde_json::Value;
+use zstd::dict::{DecoderDictionary, EncoderDictionary};
+
+/// Largest inbound frame we'll inflate, to keep compression bombs out.
+const MAX_INBOUND_SIZE: u64 = 1024 * 1024;
+
+const ZSTD_LEVEL: i32 = 3;
+
+/// Dictionary shared with clients that pick `zstd`, read from `ZSTD_DICT_PATH`
+/// and served on `/ws/zstd-dict` so both sides use the same bytes.
+pub static ZSTD_DICT: Lazy<Option<Vec<u8>>> = Lazy::new(|| {
+    std::env::var("ZSTD_DICT_PATH")
+        .ok()
+        .and_then(|path| std::fs::read(path).ok())
+});
+
+static ZSTD_ENCODER_DICT: Lazy<Option<EncoderDictionary<'static>>> = Lazy::new(|| {
+    ZSTD_DICT
+        .as_deref()
+        .map(|dict| EncoderDictionary::copy(dict, ZSTD_LEVEL))
+});
+
+static ZSTD_DECODER_DICT: Lazy<Option<DecoderDictionary<'static>>> =
+    Lazy::new(|| ZSTD_DICT.as_deref().map(DecoderDictionary::copy));
+
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    #[default]
+    Json,
+    Msgpack,
+    Cbor,
+}
+
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Compress {
+    None,
+    /// Raw deflate per frame, what every client spoke before codecs were negotiable.
+    #[default]
+    Deflate,
+    Zstd,
+}
+
+/// How frames are serialized and compressed for one connection.
+///
+/// Events travel between nodes as JSON regardless, and are only converted
+/// here on their way in and out of the socket.
+#[derive(Default, Clone, Copy)]
+pub struct Codec {
+    pub encoding: Encoding,
+    pub compress: Compress,
+}
+
+/// The outbound half of a [`Codec`], owned by the connection's writer.
+pub struct Encoder {
+    codec: Codec,
+    zstd: Option<zstd::bulk::Compressor<'static>>,
+}
+
+impl Codec {
+    pub fn encoder(self) -> Encoder {
+        self.encoder_with(ZSTD_ENCODER_DICT.as_ref())
+    }
+
+    fn encoder_with(self, dict: Option<&'static EncoderDictionary<'static>>) -> Encoder {
+        let zstd = (self.compress == Compress::Zstd).then(|| match dict {
+            Some(dict) => zstd::bulk::Compressor::with_prepared_dictionary(dict).unwrap(),
+            None => zstd::bulk::Compressor::new(ZSTD_LEVEL).unwrap(),
+        });
+
+        Encoder { codec: self, zstd }
+    }
+
+    /// Turns an inbound message back into a JSON frame.
+    pub fn decode(&self, msg: Message) -> Result<Value, &'static str> {
+        self.decode_with(msg, ZSTD_DECODER_DICT.as_ref())
+    }
+
+    fn decode_with(
+        &self,
+        msg: Message,
+        dict: Option<&DecoderDictionary<'static>>,
+    ) -> Result<Value, &'static str> {
+        let bytes = match msg {
+            Message::Text(text) => {
+                return serde_json::from_str(&text).map_err(|_| "Invalid Payload");
+            }
+            Message::Binary(bytes) => bytes,
+            _ => return Err("Unexpected frame"),
+        };
+
+        let mut body = Vec::new();
+        match self.compress {
+            Compress::None => body.extend_from_slice(&bytes),
+            Compress::Deflate => {
+                DeflateDecoder::new(&bytes[..])
+                    .take(MAX_INBOUND_SIZE)
+                    .read_to_end(&mut body)
+                    .map_err(|_| "Decompression failed")?;
+            }
+            Compress::Zstd => {
+                let decoder = match dict {
+                    Some(dict) => {
+                        zstd::stream::read::Decoder::with_prepared_dictionary(&bytes[..], dict)
+                    }
+                    None => zstd::stream::read::Decoder::with_buffer(&bytes[..]),
+                };
+                decoder
+                    .map_err(|_| "Decompression failed")?
+                    .take(MAX_INBOUND_SIZE)
+                    .read_to_end(&mut body)
+                    .map_err(|_| "Decompression failed")?;
+            }
+        }
+
+        match self.encoding {
+            Encoding::Json => serde_json::from_slice(&body).map_err(|_| "Invalid Payload"),
+            Encoding::Msgpack => rmp_serde::from_slice(&body).map_err(|_| "Invalid Payload"),
+            Encoding::Cbor => ciborium::from_reader(&body[..]).map_err(|_| "Invalid Payload"),
+        }
+    }
+}
+
+impl Encoder {
+    /// Turns a JSON frame into the message this client asked for.
+    pub fn encode(&mut self, frame: String) -> Message {
+        let body = match self.codec.encoding {
+            Encoding::Json if self.codec.compress == Compress::None => {
+                return Message::text(frame);
+            }
+            Encoding::Json => frame.into_bytes(),
+            Encoding::Msgpack => {
+                let value: Value = serde_json::from_str(&frame).unwrap();
+                rmp_serde::to_vec_named(&value).unwrap()
+            }
+            Encoding::Cbor => {
+                let value: Value = serde_json::from_str(&frame).unwrap();
+                let mut body = Vec::new();
+                ciborium::into_writer(&value, &mut body).unwrap();
+                body
+            }
+        };
+
+        match (self.codec.compress, &mut self.zstd) {
+            (Compress::Deflate, _) => {
+                let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(&body).unwrap();
+                Message::binary(encoder.finish().unwrap())
+            }
+            (Compress::Zstd, Some(zstd)) => Message::binary(zstd.compress(&body).unwrap()),
+            _ => Message::binary(body),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    const ENCODINGS: [Encoding; 3] = [Encoding::Json, Encoding::Msgpack, Encoding::Cbor];
+
+    const COMPRESSIONS: [Compress; 3] = [Compress::None, Compress::Deflate, Compress::Zstd];
+
+    /// A raw content dictionary, which zstd takes as readily as a trained one.
+    const DICT: &[u8] =
+        br#"{"type":"MESSAGE_CREATE","data":{"channel_id":"","author_id":""},"seq":"#;
+
+    fn event() -> Value {
+        json!({
+            "type": "MESSAGE_CREATE",
+            "data": {
+                "id": "ck0",
+                "channel_id": "ck1",
+                "author_id": "ck2",
+                "content": "héllo ".repeat(50),
+                "attachments": [],
+                "pinned": false,
+                "edited_at": null,
+                "nonce": 42,
+            },
+            "seq": 7,
+            "v": 1,
+        })
+    }
+
+    /// Leaked like the one loaded from `ZSTD_DICT_PATH`, which the encoder
+    /// borrows for good.
+    fn encoder_dict() -> &'static EncoderDictionary<'static> {
+        Box::leak(Box::new(EncoderDictionary::copy(DICT, ZSTD_LEVEL)))
+    }
+
+    fn codecs() -> impl Iterator<Item = Codec> {
+        ENCODINGS.into_iter().flat_map(|encoding| {
+            COMPRESSIONS
+                .into_iter()
+                .map(move |compress| Codec { encoding, compress })
+        })
+    }
+
+    #[test]
+    fn round_trips_every_codec() {
+        for codec in codecs() {
+            let msg = codec.encoder().encode(event().to_string());
+            assert_eq!(codec.decode(msg), Ok(event()));
+        }
+    }
+
+    #[test]
+    fn round_trips_every_codec_with_a_zstd_dictionary() {
+        let encoder_dict = encoder_dict();
+        let decoder_dict = DecoderDictionary::copy(DICT);
+
+        for codec in codecs() {
+            let msg = codec
+                .encoder_with(Some(encoder_dict))
+                .encode(event().to_string());
+            assert_eq!(codec.decode_with(msg, Some(&decoder_dict)), Ok(event()));
+        }
+    }
+
+    #[test]
+    fn zstd_needs_the_same_dictionary() {
+        let codec = Codec {
+            encoding: Encoding::Json,
+            compress: Compress::Zstd,
+        };
+        let msg = codec
+            .encoder_with(Some(encoder_dict()))
+            .encode(event().to_string());
+
+        assert_eq!(codec.decode_with(msg, None), Err("Decompression failed"));
+    }
+
+    #[test]
+    fn encodes_uncompressed_json_as_text() {
+        let codec = Codec {
+            encoding: Encoding::Json,
+            compress: Compress::None,
+        };
+
+        assert!(matches!(
+            codec.encoder().encode("{}".into()),
+            Message::Text(_)
+        ));
+        for codec in
+            codecs().filter(|c| c.encoding != Encoding::Json || c.compress != Compress::None)
+        {
+            assert!(matches!(
+                codec.encoder().encode("{}".into()),
+                Message::Binary(_)
+            ));
+        }
+    }
+
+    #[test]
+    fn encoder_keeps_state_between_frames() {
+        for codec in codecs() {
+            let mut encoder = codec.encoder();
+            for n in 0..3 {
+                let mut event = event();
+                event["seq"] = json!(n);
+                let msg = encoder.encode(event.to_string());
+                assert_eq!(codec.decode(msg), Ok(event));
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        let codec = Codec {
+            encoding: Encoding::Msgpack,
+            compress: Compress::Deflate,
+        };
+        let msg = Message::binary(vec![0xff; 16]);
+
+        assert!(codec.decode(msg).is_err());
+        assert_eq!(
+            codec.decode(Message::text("not json")),
+            Err("Invalid Payload")
+        );
+    }
+}
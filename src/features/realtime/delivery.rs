@@ -0,0 +1,187 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    time::Duration,
+};
+
+use cuid::cuid1;
+use once_cell::sync::Lazy;
+use redis::{
+    aio::MultiplexedConnection,
+    streams::{
+        StreamAutoClaimOptions, StreamAutoClaimReply, StreamId, StreamReadOptions, StreamReadReply,
+    },
+    AsyncCommands, RedisResult,
+};
+use serde_json::Value;
+
+use crate::RD_POOL;
+
+use super::{hub, outbox::EVENTS_STREAM, push};
+
+/// Identifies this process to Redis. Set `NODE_ID` to keep it across
+/// restarts, so pending events are picked up again by the same node.
+pub static NODE_ID: Lazy<String> =
+    Lazy::new(|| std::env::var("NODE_ID").unwrap_or_else(|_| cuid1().unwrap()));
+
+/// Group shared by every node, each event being handled by one of them.
+const PUSH_GROUP: &str = "push";
+
+const READ_BATCH: usize = 100;
+
+/// How long a read waits for new events before checking on stale ones.
+const READ_BLOCK: Duration = Duration::from_secs(5);
+
+/// Events a consumer held on to for this long are presumed lost with it and
+/// handed to someone else.
+const CLAIM_IDLE: Duration = Duration::from_secs(60);
+
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Events a node remembers delivering, to drop the duplicates a relay retry
+/// can produce.
+const SEEN_CAPACITY: usize = 10_000;
+
+#[derive(Clone, Copy)]
+enum Consumer {
+    /// Delivers every event to the sockets on this node.
+    Node,
+    /// Feeds the push notification pipeline.
+    Push,
+}
+
+/// Recently handled event ids, oldest first.
+#[derive(Default)]
+struct Seen {
+    order: VecDeque<String>,
+    ids: HashSet<String>,
+}
+
+impl Seen {
+    /// Remembers `id`, returning `false` if it was already there.
+    fn insert(&mut self, id: &str) -> bool {
+        if !self.ids.insert(id.to_owned()) {
+            return false;
+        }
+
+        self.order.push_back(id.to_owned());
+        if self.order.len() > SEEN_CAPACITY {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+
+        true
+    }
+}
+
+/// Handles one entry. Returns `false` to leave it pending for a retry.
+async fn handle(
+    consumer: Consumer,
+    conn: &mut MultiplexedConnection,
+    seen: &mut Seen,
+    entry: &StreamId,
+) -> bool {
+    let (Some(event_id), Some(topic), Some(payload)) = (
+        entry.get::<String>("event_id"),
+        entry.get::<String>("topic"),
+        entry.get::<String>("payload"),
+    ) else {
+        // Nothing to be done about a malformed entry but to skip it.
+        return true;
+    };
+
+    match consumer {
+        Consumer::Node => {
+            if seen.insert(&event_id) {
+                hub::deliver(&topic, payload.into_bytes());
+            }
+            true
+        }
+        Consumer::Push => {
+            let Ok(event) = serde_json::from_str::<Value>(&payload) else {
+                return true;
+            };
+            push::handle(conn, &event_id, &topic, &event).await
+        }
+    }
+}
+
+/// Reads and handles events until the connection fails.
+async fn consume(
+    consumer: Consumer,
+    group: &str,
+    conn: &mut MultiplexedConnection,
+    seen: &mut Seen,
+) -> RedisResult<()> {
+    let read_options = StreamReadOptions::default()
+        .group(group, &*NODE_ID)
+        .count(READ_BATCH)
+        .block(READ_BLOCK.as_millis() as usize);
+
+    loop {
+        let claimed: StreamAutoClaimReply = conn
+            .xautoclaim_options(
+                EVENTS_STREAM,
+                group,
+                &*NODE_ID,
+                CLAIM_IDLE.as_millis() as usize,
+                "0-0",
+                StreamAutoClaimOptions::default().count(READ_BATCH),
+            )
+            .await?;
+
+        let read: Option<StreamReadReply> = conn
+            .xread_options(&[EVENTS_STREAM], &[">"], &read_options)
+            .await?;
+
+        let entries = claimed.claimed.into_iter().chain(
+            read.into_iter()
+                .flat_map(|read| read.keys)
+                .flat_map(|key| key.ids),
+        );
+
+        for entry in entries {
+            if handle(consumer, conn, seen, &entry).await {
+                conn.xack::<_, _, _, ()>(EVENTS_STREAM, group, &[&entry.id])
+                    .await?;
+            }
+        }
+    }
+}
+
+fn spawn_consumer(consumer: Consumer, group: String) {
+    tokio::spawn(async move {
+        let client = RD_POOL.get().unwrap().clone();
+        let mut seen = Seen::default();
+
+        loop {
+            // Blocking reads hold up everything queued behind them, so each
+            // consumer gets a connection of its own.
+            if let Ok(mut conn) = client.get_multiplexed_async_connection().await {
+                // A node only cares about events from the moment it's up,
+                // while pushes are owed for anything the group hasn't seen.
+                let start = match consumer {
+                    Consumer::Node => "$",
+                    Consumer::Push => "0",
+                };
+                // Fails with BUSYGROUP when the group is already there.
+                conn.xgroup_create_mkstream::<_, _, _, ()>(EVENTS_STREAM, &group, start)
+                    .await
+                    .ok();
+
+                if let Err(err) = consume(consumer, &group, &mut conn, &mut seen).await {
+                    eprintln!("Event consumer {} stopped: {}", group, err);
+                }
+            }
+
+            tokio::time::sleep(RETRY_DELAY).await;
+        }
+    });
+}
+
+/// Starts consuming [`EVENTS_STREAM`]: one group per node so each delivers
+/// every event to its own sockets, and one shared group for push.
+pub fn spawn() {
+    spawn_consumer(Consumer::Node, format!("node:{}", *NODE_ID));
+    spawn_consumer(Consumer::Push, PUSH_GROUP.to_owned());
+}
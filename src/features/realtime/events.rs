@@ -1,9 +1,375 @@
-use serde::Deserialize;
+use std::{collections::HashMap, time::Duration};
+
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+use super::presence::{CustomStatus, Presence, Status};
+
+/// Bumped on breaking changes to the frames below. Clients that don't send
+/// `v` are assumed to speak the current version.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Events sent by clients.
+#[derive(Deserialize, JsonSchema)]
+#[serde(tag = "type", content = "data", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ClientEvent {
+    Ping,
+    PushToken {
+        token: String,
+    },
+    PresenceSet {
+        status: Status,
+        custom_status: Option<CustomStatus>,
+    },
+}
+
+impl ClientEvent {
+    /// The `type` the event arrived with.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ClientEvent::Ping => "PING",
+            ClientEvent::PushToken { .. } => "PUSH_TOKEN",
+            ClientEvent::PresenceSet { .. } => "PRESENCE_SET",
+        }
+    }
+}
+
+/// Events sent by the server, either directly or fanned out over Redis.
+#[derive(Serialize, JsonSchema)]
+#[serde(tag = "type", content = "data", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ServerEvent {
+    Init(Box<InitData>),
+    Resumed {
+        resume_id: String,
+        seq: u64,
+        heartbeat_interval: u64,
+    },
+    Pong,
+    /// The server is going away. Reconnect after `delay` milliseconds,
+    /// which lands the client on another node.
+    Reconnect {
+        delay: u64,
+    },
+    PresenceUpdate(PresenceUpdate),
+    /// Sent to every member of a new channel on their own topic, and to
+    /// members joining an existing one.
+    ChannelCreate(Channel),
+    ChannelUpdate(Channel),
+    ChannelMemberAdd(ChannelMember),
+    ChannelMemberRemove(ChannelMember),
+    ChannelRolesUpdate(ChannelRoles),
+    ChannelPinsUpdate(ChannelPins),
+    MessageCreate(Message),
+    /// A member read up to a message. Goes to the channel when they share
+    /// read receipts, and only to their own sessions otherwise.
+    MessageAck(MessageAck),
+    /// A friend request was sent or received.
+    RelationshipAdd(Relationship),
+    /// A friend request was accepted.
+    RelationshipUpdate(Relationship),
+    /// A friend request was declined or cancelled, or a friend removed.
+    RelationshipRemove(Relationship),
+    /// The user blocked someone, on their own topic only.
+    BlockAdd(Block),
+    BlockRemove(Block),
+    Error(ErrorData),
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct InitData {
+    pub me: Value,
+    pub channels: Value,
+    pub relationships: Value,
+    pub users: Value,
+    pub presence: Map<String, Value>,
+    pub session: String,
+    pub push_token: Value,
+    pub heartbeat_interval: u64,
+    pub resume_id: String,
+    pub seq: u64,
+}
+
+/// A channel and who is in it, longest-standing members first.
+#[derive(Serialize, JsonSchema, Clone)]
+pub struct Channel {
+    pub id: String,
+    pub name: Option<String>,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub owner_id: Option<String>,
+    /// Permission bits every member has, before roles add to them.
+    pub default_permissions: i64,
+    pub topic: Option<String>,
+    pub icon: Option<String>,
+    pub archived_at: Option<DateTime<Utc>>,
+    /// How many messages can be pinned at once.
+    pub max_pins: i32,
+    pub created_at: DateTime<Utc>,
+    pub members: Vec<String>,
+    /// The receiving member's own settings, only sent to them.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub settings: Option<MemberSettings>,
+}
+
+/// Which messages in a channel notify a member.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum Notify {
+    All,
+    /// Only messages mentioning them as `<@user_id>`.
+    Mentions,
+    None,
+}
+
+impl Notify {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Notify::All => "ALL",
+            Notify::Mentions => "MENTIONS",
+            Notify::None => "NONE",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "ALL" => Some(Notify::All),
+            "MENTIONS" => Some(Notify::Mentions),
+            "NONE" => Some(Notify::None),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, JsonSchema, Clone)]
+pub struct MemberSettings {
+    pub notify: Notify,
+    /// No notifications at all before this.
+    pub muted_until: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, JsonSchema, Clone, sqlx::FromRow)]
+pub struct Role {
+    pub id: String,
+    pub name: String,
+    pub permissions: i64,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Every role of a group and who holds which, by user id.
+#[derive(Serialize, JsonSchema, Clone)]
+pub struct ChannelRoles {
+    pub channel_id: String,
+    pub roles: Vec<Role>,
+    pub members: HashMap<String, Vec<String>>,
+}
+
+#[derive(Serialize, JsonSchema, Clone, sqlx::FromRow)]
+pub struct Pin {
+    pub message_id: String,
+    pub pinned_by: String,
+    pub pinned_at: DateTime<Utc>,
+}
+
+/// Every pin of a channel, most recently pinned first.
+#[derive(Serialize, JsonSchema, Clone)]
+pub struct ChannelPins {
+    pub channel_id: String,
+    pub pins: Vec<Pin>,
+}
+
+/// A friendship or pending friend request, as seen by one side of it.
+#[derive(Serialize, JsonSchema, Clone)]
+pub struct Relationship {
+    pub id: String,
+    /// The other side.
+    pub user_id: String,
+    /// Whether the other side sent the request.
+    pub incoming: bool,
+    pub accepted_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct Block {
+    pub user_id: String,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct ChannelMember {
+    pub channel_id: String,
+    pub user_id: String,
+}
+
+#[derive(Serialize, JsonSchema, sqlx::FromRow)]
+pub struct Message {
+    pub id: String,
+    pub channel_id: String,
+    pub author_id: String,
+    pub content: String,
+    /// `DEFAULT` for what users write, otherwise a system message about the
+    /// user whose id is in `content`.
+    #[serde(rename = "type")]
+    #[sqlx(rename = "type")]
+    pub kind: String,
+    pub reply_to: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct MessageAck {
+    pub channel_id: String,
+    pub user_id: String,
+    pub message_id: String,
+    pub read_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct PresenceUpdate {
+    pub id: String,
+    #[serde(flatten)]
+    pub presence: Presence,
+}
+
+#[derive(Serialize, JsonSchema)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorCode {
+    InvalidPayload,
+    UnknownEvent,
+    UnsupportedVersion,
+    RateLimited,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct ErrorData {
+    pub code: ErrorCode,
+    pub message: String,
+    /// Milliseconds until a `RATE_LIMITED` event may be sent again.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_after: Option<u64>,
+}
+
+impl ErrorData {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        ErrorData {
+            code,
+            message: message.into(),
+            retry_after: None,
+        }
+    }
+
+    pub fn rate_limited(retry_after: Duration) -> Self {
+        ErrorData {
+            code: ErrorCode::RateLimited,
+            message: "You are being rate limited".to_owned(),
+            retry_after: Some(retry_after.as_millis() as u64),
+        }
+    }
+}
+
+impl ServerEvent {
+    /// Serializes the event into the JSON frame clients receive, minus `seq`.
+    pub fn frame(&self) -> String {
+        let mut frame = serde_json::to_value(self).unwrap();
+        frame["v"] = json!(PROTOCOL_VERSION);
+        frame.to_string()
+    }
+}
 
 #[derive(Deserialize)]
-#[serde(untagged)]
-pub enum RealTimeEvents {
-  Ping,
-  // ChatStatus { channel_id: String, status: String },
-  PushToken { token: String }
-}
\ No newline at end of file
+struct Frame {
+    v: Option<u32>,
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    data: Value,
+}
+
+/// Parses an inbound frame, describing what was wrong with it otherwise.
+pub fn parse(frame: Value) -> Result<ClientEvent, ErrorData> {
+    let frame: Frame = serde_json::from_value(frame)
+        .map_err(|_| ErrorData::new(ErrorCode::InvalidPayload, "Malformed frame"))?;
+
+    if frame.v.is_some_and(|v| v != PROTOCOL_VERSION) {
+        return Err(ErrorData::new(
+            ErrorCode::UnsupportedVersion,
+            format!("Only protocol version {} is supported", PROTOCOL_VERSION),
+        ));
+    }
+
+    serde_json::from_value(json!({ "type": &frame.kind, "data": frame.data })).map_err(|err| {
+        // serde has no dedicated error kind for an unrecognised tag, and
+        // enums inside `data` fail the same way.
+        if err
+            .to_string()
+            .starts_with(&format!("unknown variant `{}`", frame.kind))
+        {
+            ErrorData::new(
+                ErrorCode::UnknownEvent,
+                format!("Unknown event {}", frame.kind),
+            )
+        } else {
+            ErrorData::new(ErrorCode::InvalidPayload, err.to_string())
+        }
+    })
+}
+
+/// JSON Schema of every client and server event, for the frontend to generate types from.
+pub fn schema() -> Value {
+    json!({
+        "version": PROTOCOL_VERSION,
+        "client": schemars::schema_for!(ClientEvent),
+        "server": schemars::schema_for!(ServerEvent),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rejected(frame: Value) -> ErrorData {
+        match parse(frame) {
+            Ok(_) => panic!("frame was accepted"),
+            Err(err) => err,
+        }
+    }
+
+    #[test]
+    fn parses_frames_with_and_without_version() {
+        assert!(matches!(
+            parse(json!({ "v": PROTOCOL_VERSION, "type": "PING" })),
+            Ok(ClientEvent::Ping)
+        ));
+        assert!(matches!(
+            parse(json!({ "type": "PUSH_TOKEN", "data": { "token": "t" } })),
+            Ok(ClientEvent::PushToken { token }) if token == "t"
+        ));
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        let err = rejected(json!({ "v": PROTOCOL_VERSION + 1, "type": "PING" }));
+        assert_eq!(json!(err.code), "UNSUPPORTED_VERSION");
+    }
+
+    #[test]
+    fn rejects_unknown_events() {
+        let err = rejected(json!({ "type": "TELEPORT", "data": {} }));
+        assert_eq!(json!(err.code), "UNKNOWN_EVENT");
+        assert_eq!(err.message, "Unknown event TELEPORT");
+    }
+
+    #[test]
+    fn rejects_bad_payloads() {
+        let err = rejected(json!({ "type": "PRESENCE_SET", "data": { "status": "AWAY" } }));
+        assert_eq!(json!(err.code), "INVALID_PAYLOAD");
+
+        let err = rejected(json!({ "type": "PUSH_TOKEN" }));
+        assert_eq!(json!(err.code), "INVALID_PAYLOAD");
+
+        let err = rejected(json!({ "data": {} }));
+        assert_eq!(json!(err.code), "INVALID_PAYLOAD");
+        assert_eq!(err.message, "Malformed frame");
+    }
+}
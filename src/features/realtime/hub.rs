@@ -0,0 +1,282 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use futures::StreamExt;
+use once_cell::sync::OnceCell;
+use redis::{Client, Msg};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::RD_POOL;
+
+/// Delay before the first attempt to reconnect, doubled up to [`MAX_BACKOFF`].
+const MIN_BACKOFF: Duration = Duration::from_millis(100);
+
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+/// Messages a subscriber may have waiting before it's considered stalled and
+/// dropped, so a stuck socket can't hold on to an ever growing backlog.
+const ROUTE_CAPACITY: usize = 1024;
+
+static HUB: OnceCell<Arc<Hub>> = OnceCell::new();
+
+/// A message published on one of the topics a [`Subscriber`] follows.
+pub struct Published {
+    pub topic: String,
+    pub payload: Vec<u8>,
+}
+
+/// Where a topic's messages go for one subscriber.
+#[derive(Clone)]
+struct Route {
+    tx: mpsc::Sender<Arc<Published>>,
+    /// Raised once the subscriber overflowed, after which it gets nothing.
+    lagged: Arc<AtomicBool>,
+}
+
+impl Route {
+    /// Hands `published` over, unless the subscriber fell too far behind.
+    fn send(&self, published: &Arc<Published>) -> bool {
+        if self.lagged.load(Ordering::Relaxed) {
+            return false;
+        }
+
+        match self.tx.try_send(published.clone()) {
+            Ok(()) => true,
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                self.lagged.store(true, Ordering::Relaxed);
+                false
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => false,
+        }
+    }
+}
+
+enum Command {
+    /// Topics nobody on this node followed yet. Acknowledged once Redis
+    /// confirmed them, or right away when there are none, which still waits
+    /// for earlier subscriptions to go through.
+    Subscribe(Vec<String>, oneshot::Sender<()>),
+    /// Topics the last local subscriber let go of.
+    Unsubscribe(Vec<String>),
+}
+
+/// The one Redis pub/sub connection of this process, shared by every socket.
+///
+/// Topics are reference counted: Redis hears about a topic when the first
+/// local socket follows it and again when the last one leaves, and incoming
+/// messages are routed to sockets in memory.
+struct Hub {
+    topics: Mutex<HashMap<String, HashMap<u64, Route>>>,
+    commands: mpsc::UnboundedSender<Command>,
+    next_id: AtomicU64,
+}
+
+impl Hub {
+    fn get() -> Arc<Hub> {
+        HUB.get_or_init(|| {
+            let (commands, rx) = mpsc::unbounded_channel();
+            let hub = Arc::new(Hub {
+                topics: Mutex::new(HashMap::new()),
+                commands,
+                next_id: AtomicU64::new(0),
+            });
+
+            tokio::spawn(run(RD_POOL.get().unwrap().clone(), hub.clone(), rx));
+            hub
+        })
+        .clone()
+    }
+
+    fn route(&self, msg: Msg) {
+        self.dispatch(msg.get_channel_name(), msg.get_payload_bytes().to_vec());
+    }
+
+    fn dispatch(&self, topic: &str, payload: Vec<u8>) {
+        let mut topics = self.topics.lock().unwrap();
+        let Some(routes) = topics.get_mut(topic) else {
+            return;
+        };
+
+        let published = Arc::new(Published {
+            topic: topic.to_owned(),
+            payload,
+        });
+
+        // Stalled subscribers lose their routes here and are disconnected
+        // once they drain what they already have.
+        routes.retain(|_, route| route.send(&published));
+
+        if routes.is_empty() {
+            topics.remove(topic);
+            self.commands
+                .send(Command::Unsubscribe(vec![topic.to_owned()]))
+                .ok();
+        }
+    }
+}
+
+/// Owns the connection, reconnecting and resubscribing to every followed
+/// topic whenever it drops, e.g. when Redis restarts.
+async fn run(client: Client, hub: Arc<Hub>, mut commands: mpsc::UnboundedReceiver<Command>) {
+    let mut backoff = MIN_BACKOFF;
+
+    loop {
+        let (mut sink, mut stream) = match client.get_async_pubsub().await {
+            Ok(pubsub) => pubsub.split(),
+            Err(_) => {
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+                continue;
+            }
+        };
+
+        // Commands still queued are replayed on top of this, which is fine
+        // since they only ever move Redis towards what the map says.
+        let topics: Vec<String> = hub.topics.lock().unwrap().keys().cloned().collect();
+        if !topics.is_empty() && sink.subscribe(&topics).await.is_err() {
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+            continue;
+        }
+
+        backoff = MIN_BACKOFF;
+
+        loop {
+            tokio::select! {
+                msg = stream.next() => match msg {
+                    Some(msg) => hub.route(msg),
+                    None => break,
+                },
+
+                command = commands.recv() => match command {
+                    // A failure means the connection is gone, and the topics
+                    // are picked up again once it's back.
+                    Some(Command::Subscribe(topics, ack)) => {
+                        if !topics.is_empty() {
+                            sink.subscribe(&topics).await.ok();
+                        }
+                        ack.send(()).ok();
+                    }
+                    Some(Command::Unsubscribe(topics)) => {
+                        sink.unsubscribe(&topics).await.ok();
+                    }
+                    None => return,
+                },
+            }
+        }
+
+        println!("Redis pub/sub connection lost, reconnecting");
+    }
+}
+
+/// Hands `payload` to the local subscribers of `topic`, for events that
+/// reach this node some other way than pub/sub.
+pub fn deliver(topic: &str, payload: Vec<u8>) {
+    Hub::get().dispatch(topic, payload);
+}
+
+/// A socket's view of the shared connection. Dropping it lets go of every
+/// topic it followed.
+pub struct Subscriber {
+    id: u64,
+    hub: Arc<Hub>,
+    topics: HashSet<String>,
+    route: Route,
+    rx: mpsc::Receiver<Arc<Published>>,
+}
+
+/// A fresh subscriber that follows nothing yet.
+pub fn subscriber() -> Subscriber {
+    let hub = Hub::get();
+    let (tx, rx) = mpsc::channel(ROUTE_CAPACITY);
+
+    Subscriber {
+        id: hub.next_id.fetch_add(1, Ordering::Relaxed),
+        hub,
+        topics: HashSet::new(),
+        route: Route {
+            tx,
+            lagged: Arc::new(AtomicBool::new(false)),
+        },
+        rx,
+    }
+}
+
+impl Subscriber {
+    /// Follows `topics`, returning once Redis delivers their messages.
+    pub async fn subscribe(&mut self, topics: &[String]) {
+        let (ack, acked) = oneshot::channel();
+
+        {
+            let mut routes = self.hub.topics.lock().unwrap();
+            let mut new = Vec::new();
+
+            for topic in topics {
+                if !self.topics.insert(topic.clone()) {
+                    continue;
+                }
+
+                let subscribers = routes.entry(topic.clone()).or_insert_with(|| {
+                    new.push(topic.clone());
+                    HashMap::new()
+                });
+                subscribers.insert(self.id, self.route.clone());
+            }
+
+            // Sent under the lock so commands for a topic queue up in the
+            // same order its count went up and down.
+            self.hub.commands.send(Command::Subscribe(new, ack)).ok();
+        }
+
+        acked.await.ok();
+    }
+
+    pub fn unsubscribe(&mut self, topics: &[String]) {
+        let mut routes = self.hub.topics.lock().unwrap();
+        let mut gone = Vec::new();
+
+        for topic in topics {
+            if !self.topics.remove(topic) {
+                continue;
+            }
+
+            if let Some(subscribers) = routes.get_mut(topic) {
+                subscribers.remove(&self.id);
+                if subscribers.is_empty() {
+                    routes.remove(topic);
+                    gone.push(topic.clone());
+                }
+            }
+        }
+
+        // An empty UNSUBSCRIBE would drop every topic.
+        if !gone.is_empty() {
+            self.hub.commands.send(Command::Unsubscribe(gone)).ok();
+        }
+    }
+
+    /// Waits for the next message on any followed topic. `None` means the
+    /// subscriber fell too far behind and was dropped, so it has missed
+    /// messages and should start over.
+    pub async fn recv(&mut self) -> Option<Arc<Published>> {
+        // Only a full queue raises the flag, so it's seen before this could
+        // wait on an empty one.
+        if self.route.lagged.load(Ordering::Relaxed) {
+            return None;
+        }
+        self.rx.recv().await
+    }
+}
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        let topics: Vec<String> = self.topics.iter().cloned().collect();
+        self.unsubscribe(&topics);
+    }
+}
@@ -1,2 +1,15 @@
-pub mod routes;
-pub mod events;
\ No newline at end of file
+pub mod codec;
+pub mod delivery;
+pub mod events;
+pub mod hub;
+pub mod outbox;
+pub mod outbound;
+pub mod presence;
+pub mod push;
+pub mod ratelimit;
+pub mod registry;
+pub mod replay;
+pub mod routes;
+pub mod shutdown;
+pub mod subscriptions;
+pub mod transport;
\ No newline at end of file
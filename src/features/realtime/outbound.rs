@@ -0,0 +1,116 @@
+use std::{sync::Arc, time::Duration};
+
+use axum::extract::ws::{CloseFrame, Message};
+use tokio::{
+    sync::{mpsc, Notify},
+    task::JoinHandle,
+};
+
+use super::{codec::Encoder, transport::Sender};
+
+/// Frames a connection may have queued before it counts as a slow consumer.
+const QUEUE_CAPACITY: usize = 256;
+
+/// Close code sent to a client that can't keep up with its events.
+pub const SLOW_CONSUMER_CODE: u16 = 4008;
+
+/// How long the close frame may take to go out to a slow consumer.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    /// Ephemeral events (typing and the like) that are fine to lose under load.
+    Low,
+    Normal,
+}
+
+impl Priority {
+    pub fn of(kind: &str) -> Self {
+        match kind {
+            "TYPING_START" | "TYPING_STOP" => Priority::Low,
+            _ => Priority::Normal,
+        }
+    }
+}
+
+enum Outgoing {
+    /// A JSON frame, encoded by the writer with the client's codec.
+    Frame(String),
+    /// A WebSocket control message, sent as is.
+    Control(Message),
+}
+
+/// Queue in front of the single task that writes to a socket.
+///
+/// When the queue is full, low-priority frames are dropped and anything else
+/// gets the connection closed, since the client has fallen too far behind.
+#[derive(Clone)]
+pub struct Outbound {
+    tx: mpsc::Sender<Outgoing>,
+    overflow: Arc<Notify>,
+}
+
+impl Outbound {
+    /// Spawns the writer for `sink`. The task ends once every `Outbound` is
+    /// dropped and the queue is drained, when the socket errors, or after a
+    /// slow consumer was disconnected.
+    pub fn spawn(mut sink: Sender, mut encoder: Encoder) -> (Self, JoinHandle<()>) {
+        let (tx, mut rx) = mpsc::channel::<Outgoing>(QUEUE_CAPACITY);
+        let overflow = Arc::new(Notify::new());
+
+        let overflowed = overflow.clone();
+        let writer = tokio::spawn(async move {
+            loop {
+                let msg = tokio::select! {
+                    _ = overflowed.notified() => break,
+                    msg = rx.recv() => match msg {
+                        Some(Outgoing::Frame(frame)) => encoder.encode(frame),
+                        Some(Outgoing::Control(msg)) => msg,
+                        None => return,
+                    },
+                };
+
+                tokio::select! {
+                    _ = overflowed.notified() => break,
+                    sent = sink.send(msg) => if sent.is_err() {
+                        return;
+                    },
+                }
+            }
+
+            let close = Message::Close(Some(CloseFrame {
+                code: SLOW_CONSUMER_CODE,
+                reason: "Too slow to keep up".into(),
+            }));
+            tokio::time::timeout(CLOSE_TIMEOUT, sink.send(close))
+                .await
+                .ok();
+        });
+
+        (Outbound { tx, overflow }, writer)
+    }
+
+    /// Queues a JSON `frame` without waiting. Returns `false` once the
+    /// connection is going away, either because the writer stopped or it
+    /// overflowed.
+    pub fn send(&self, frame: String, priority: Priority) -> bool {
+        self.enqueue(Outgoing::Frame(frame), priority)
+    }
+
+    /// Queues a WebSocket control message, like a ping or a close.
+    pub fn control(&self, msg: Message) -> bool {
+        self.enqueue(Outgoing::Control(msg), Priority::Normal)
+    }
+
+    fn enqueue(&self, outgoing: Outgoing, priority: Priority) -> bool {
+        match self.tx.try_send(outgoing) {
+            Ok(()) => true,
+            Err(mpsc::error::TrySendError::Full(_)) if priority == Priority::Low => true,
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                self.overflow.notify_one();
+                false
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => false,
+        }
+    }
+}
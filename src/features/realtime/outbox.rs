@@ -0,0 +1,170 @@
+use std::time::Duration;
+
+use cuid::cuid1;
+use redis::aio::MultiplexedConnection;
+use serde_json::Value;
+use sqlx::{types::Json, PgConnection};
+use tokio::time::Instant;
+
+use crate::{utils::log_error, DB_POOL, RD_POOL};
+
+use super::events::ServerEvent;
+
+/// Redis Stream every committed event ends up on.
+pub const EVENTS_STREAM: &str = "events";
+
+/// Rough cap on the stream, far more than consumers should ever lag behind.
+const STREAM_MAXLEN: usize = 100_000;
+
+const RELAY_BATCH: i64 = 100;
+
+/// How often an idle relay looks for new events.
+const RELAY_INTERVAL: Duration = Duration::from_millis(200);
+
+/// How long relayed events are kept around before being cleaned up.
+const RETENTION: &str = "1 day";
+
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Taken for the duration of a batch so only one node relays at a time,
+/// which keeps events in commit order on the stream.
+const RELAY_LOCK: i64 = 0x6f7574626f78;
+
+#[derive(sqlx::FromRow)]
+struct OutboxRow {
+    id: i64,
+    event_id: String,
+    topic: String,
+    payload: Json<Value>,
+}
+
+/// Records `event` for `topic` as part of the transaction `tx`, so it goes
+/// out if and only if the change it describes is committed.
+pub async fn enqueue(
+    tx: &mut PgConnection,
+    topic: &str,
+    event: &ServerEvent,
+) -> Result<(), sqlx::Error> {
+    let payload: Value = serde_json::from_str(&event.frame()).unwrap();
+
+    sqlx::query(r#"INSERT INTO "outbox" ("event_id", "topic", "payload") VALUES ($1, $2, $3)"#)
+        .bind(cuid1().unwrap())
+        .bind(topic)
+        .bind(Json(payload))
+        .execute(tx)
+        .await?;
+
+    Ok(())
+}
+
+/// Moves one batch of committed events onto the stream. A row is only marked
+/// relayed once Redis took it, so a crash in between relays it again, which
+/// consumers tell apart by `event_id`. Returns how many went out, or `None`
+/// when Redis is unreachable.
+async fn relay_batch(conn: &mut MultiplexedConnection) -> Result<Option<usize>, sqlx::Error> {
+    let pool = DB_POOL.get().unwrap();
+    let mut tx = pool.begin().await?;
+
+    let locked: bool = sqlx::query_scalar("SELECT pg_try_advisory_xact_lock($1)")
+        .bind(RELAY_LOCK)
+        .fetch_one(&mut *tx)
+        .await?;
+
+    if !locked {
+        return Ok(Some(0));
+    }
+
+    let rows = sqlx::query_as::<_, OutboxRow>(
+        r#"SELECT "id", "event_id", "topic", "payload" FROM "outbox"
+        WHERE "relayed_at" IS NULL
+        ORDER BY "id"
+        LIMIT $1"#,
+    )
+    .bind(RELAY_BATCH)
+    .fetch_all(&mut *tx)
+    .await?;
+
+    if rows.is_empty() {
+        return Ok(Some(0));
+    }
+
+    let mut pipe = redis::pipe();
+    for row in &rows {
+        pipe.cmd("XADD")
+            .arg(EVENTS_STREAM)
+            .arg("MAXLEN")
+            .arg("~")
+            .arg(STREAM_MAXLEN)
+            .arg("*")
+            .arg("event_id")
+            .arg(&row.event_id)
+            .arg("topic")
+            .arg(&row.topic)
+            .arg("payload")
+            .arg(row.payload.0.to_string())
+            .ignore();
+    }
+
+    if let Err(err) = pipe.query_async::<()>(conn).await {
+        eprintln!("Outbox relay couldn't reach Redis: {}", err);
+        return Ok(None);
+    }
+
+    let ids: Vec<i64> = rows.iter().map(|row| row.id).collect();
+    sqlx::query(r#"UPDATE "outbox" SET "relayed_at" = now() WHERE "id" = ANY($1)"#)
+        .bind(&ids)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+
+    Ok(Some(rows.len()))
+}
+
+async fn cleanup() -> Result<(), sqlx::Error> {
+    let pool = DB_POOL.get().unwrap();
+
+    sqlx::query(&format!(
+        r#"DELETE FROM "outbox" WHERE "relayed_at" < now() - interval '{}'"#,
+        RETENTION
+    ))
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Starts relaying the outbox to [`EVENTS_STREAM`]. Every node runs one and
+/// they take turns through an advisory lock.
+pub fn spawn_relay() {
+    tokio::spawn(async move {
+        let client = RD_POOL.get().unwrap().clone();
+        let mut conn = None;
+        let mut last_cleanup = Instant::now();
+
+        loop {
+            if conn.is_none() {
+                conn = client.get_multiplexed_async_connection().await.ok();
+            }
+
+            if let Some(redis) = &mut conn {
+                match relay_batch(redis).await {
+                    // There's probably more where that came from.
+                    Ok(Some(relayed)) if relayed as i64 == RELAY_BATCH => continue,
+                    Ok(Some(_)) => {}
+                    Ok(None) => conn = None,
+                    Err(err) => log_error("Outbox_RELAY", Some(&err)),
+                }
+            }
+
+            if last_cleanup.elapsed() > CLEANUP_INTERVAL {
+                last_cleanup = Instant::now();
+                if let Err(err) = cleanup().await {
+                    log_error("Outbox_CLEANUP", Some(&err));
+                }
+            }
+
+            tokio::time::sleep(RELAY_INTERVAL).await;
+        }
+    });
+}
@@ -0,0 +1,320 @@
+use std::{collections::HashMap, time::Duration};
+
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use redis::{aio::MultiplexedConnection, AsyncCommands, Script};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::database::sql::{
+    blocks::{get_blocked_ids, has_blocked},
+    privacy::get_privacy,
+};
+
+use super::events::{PresenceUpdate, ServerEvent};
+
+/// How long a user can be without any socket before they are marked offline,
+/// so a page reload or a network switch doesn't flap their presence.
+pub const GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+/// Longest custom status text we accept.
+pub const MAX_STATUS_TEXT: usize = 128;
+
+/// Flips the user to OFFLINE only if no socket came back during the grace period.
+static GO_OFFLINE: Lazy<Script> = Lazy::new(|| {
+    Script::new(
+        r#"
+        local connections = tonumber(redis.call('HGET', KEYS[1], 'connections') or '0')
+        if connections > 0 then
+          return 0
+        end
+        redis.call('HSET', KEYS[1], 'presence', 'OFFLINE', 'connections', 0)
+        redis.call('HSET', KEYS[2], 'lastSeen', ARGV[1])
+        return 1
+        "#,
+    )
+});
+
+/// The status a user picked for themselves, independent of whether they're connected.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum Status {
+    Online,
+    Idle,
+    Dnd,
+    Invisible,
+}
+
+impl Status {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Status::Online => "ONLINE",
+            Status::Idle => "IDLE",
+            Status::Dnd => "DND",
+            Status::Invisible => "INVISIBLE",
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "ONLINE" => Some(Status::Online),
+            "IDLE" => Some(Status::Idle),
+            "DND" => Some(Status::Dnd),
+            "INVISIBLE" => Some(Status::Invisible),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
+pub struct CustomStatus {
+    pub text: Option<String>,
+    pub emoji: Option<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl CustomStatus {
+    fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(|at| at <= Utc::now())
+    }
+}
+
+/// Presence as another user is allowed to see it.
+#[derive(Serialize, JsonSchema)]
+pub struct Presence {
+    pub presence: String,
+    #[serde(rename = "lastSeen")]
+    pub last_seen: Option<String>,
+    pub custom_status: Option<CustomStatus>,
+}
+
+impl Presence {
+    /// What someone blocked by the user sees of them at all times.
+    pub fn hidden() -> Self {
+        Presence {
+            presence: "OFFLINE".to_owned(),
+            last_seen: None,
+            custom_status: None,
+        }
+    }
+
+    /// Resolves the stored fields into what gets sent over the wire.
+    ///
+    /// `masked` hides INVISIBLE behind OFFLINE, which is what everyone but
+    /// the user themselves should see.
+    fn resolve(fields: &HashMap<String, String>, last_seen: Option<String>, masked: bool) -> Self {
+        let connected = fields.get("presence").is_some_and(|p| p == "ONLINE");
+        let status = fields
+            .get("status")
+            .and_then(|s| Status::parse(s))
+            .unwrap_or(Status::Online);
+
+        if !connected || (masked && status == Status::Invisible) {
+            return Presence {
+                presence: "OFFLINE".to_owned(),
+                last_seen,
+                custom_status: None,
+            };
+        }
+
+        let custom_status = fields
+            .get("custom_status")
+            .and_then(|s| serde_json::from_str::<CustomStatus>(s).ok())
+            .filter(|s| !s.is_expired());
+
+        Presence {
+            presence: status.as_str().to_owned(),
+            last_seen,
+            custom_status,
+        }
+    }
+}
+
+fn presence_key(uid: &str) -> String {
+    format!("user:{}", uid)
+}
+
+fn last_seen_key(uid: &str) -> String {
+    format!("user-lastSeen:{}", uid)
+}
+
+/// The stored presence of `uid`, their `lastSeen`, and whether they let
+/// others see it.
+async fn load(
+    conn: &mut MultiplexedConnection,
+    uid: &str,
+) -> (HashMap<String, String>, Option<String>, bool) {
+    let (fields, last_seen): (HashMap<String, String>, Option<String>) = redis::pipe()
+        .hgetall(presence_key(uid))
+        .hget(last_seen_key(uid), "lastSeen")
+        .query_async(conn)
+        .await
+        .unwrap_or_default();
+
+    let shows_last_seen = get_privacy(conn, uid).await.show_last_seen;
+    (fields, last_seen, shows_last_seen)
+}
+
+/// Presence of `uid` as seen by their friends.
+pub async fn get(conn: &mut MultiplexedConnection, uid: &str) -> Presence {
+    let (fields, last_seen, shows_last_seen) = load(conn, uid).await;
+    Presence::resolve(&fields, last_seen.filter(|_| shows_last_seen), true)
+}
+
+/// Presence of `uid` as seen by `viewer`, which stays OFFLINE if `uid`
+/// blocked them.
+pub async fn get_for(conn: &mut MultiplexedConnection, uid: &str, viewer: &str) -> Presence {
+    if hides_from(conn, uid, viewer).await {
+        return Presence::hidden();
+    }
+    get(conn, uid).await
+}
+
+/// Sends `viewer` the presence of `uid` as they may see it now, for when a
+/// block between the two came or went.
+pub async fn refresh(conn: &mut MultiplexedConnection, uid: &str, viewer: &str) {
+    let update = ServerEvent::PresenceUpdate(PresenceUpdate {
+        id: uid.to_owned(),
+        presence: get_for(conn, uid, viewer).await,
+    });
+
+    conn.publish::<_, _, ()>(format!("U.{}", viewer), update.frame())
+        .await
+        .ok();
+}
+
+/// Whether `uid` blocked `viewer`, who then never learns how they are doing.
+pub async fn hides_from(conn: &mut MultiplexedConnection, uid: &str, viewer: &str) -> bool {
+    has_blocked(conn, uid, viewer).await
+}
+
+/// Whether push notifications to `uid` should be held back.
+pub async fn suppresses_push(conn: &mut MultiplexedConnection, uid: &str) -> bool {
+    let status: Option<String> = conn.hget(presence_key(uid), "status").await.unwrap_or(None);
+    status.as_deref().and_then(Status::parse) == Some(Status::Dnd)
+}
+
+/// Counts a new socket for `uid` and marks them ONLINE.
+///
+/// The counter lives in Redis so it covers every session on every node.
+/// Returns `true` when this is the user's only live connection.
+pub async fn connect(conn: &mut MultiplexedConnection, uid: &str) -> bool {
+    let key = presence_key(uid);
+
+    let (connections,): (i64,) = redis::pipe()
+        .atomic()
+        .hincr(&key, "connections", 1)
+        .hset(&key, "presence", "ONLINE")
+        .ignore()
+        .query_async(conn)
+        .await
+        .unwrap_or((1,));
+
+    connections == 1
+}
+
+/// Releases `connections` sockets of `uid`.
+///
+/// When they were the last ones, waits out [`GRACE_PERIOD`] and, if the user
+/// hasn't reconnected meanwhile, marks them OFFLINE. Returns whether they
+/// went offline.
+pub async fn disconnect(conn: &mut MultiplexedConnection, uid: &str, connections: i64) -> bool {
+    let remaining: i64 = conn
+        .hincr(presence_key(uid), "connections", -connections)
+        .await
+        .unwrap_or(0);
+
+    if remaining > 0 {
+        return false;
+    }
+
+    tokio::time::sleep(GRACE_PERIOD).await;
+
+    GO_OFFLINE
+        .key(presence_key(uid))
+        .key(last_seen_key(uid))
+        .arg(Utc::now().to_rfc3339())
+        .invoke_async(conn)
+        .await
+        .unwrap_or(false)
+}
+
+/// Stores the status `uid` picked, replacing any previous custom status.
+pub async fn set_status(
+    conn: &mut MultiplexedConnection,
+    uid: &str,
+    status: Status,
+    custom_status: Option<&CustomStatus>,
+) {
+    let key = presence_key(uid);
+    let mut pipe = redis::pipe();
+    pipe.atomic().hset(&key, "status", status.as_str()).ignore();
+
+    match custom_status {
+        Some(custom) => pipe
+            .hset(
+                &key,
+                "custom_status",
+                serde_json::to_string(custom).unwrap(),
+            )
+            .ignore(),
+        None => pipe.hdel(&key, "custom_status").ignore(),
+    };
+
+    pipe.query_async::<()>(conn).await.ok();
+}
+
+/// Publishes the current presence of `uid` as a `PRESENCE_UPDATE`.
+///
+/// Friends get the masked view, while the user's own sessions get their real
+/// status so every device stays in sync. Users they blocked get nothing.
+pub async fn broadcast(conn: &mut MultiplexedConnection, uid: &str, friend_ids: &[String]) {
+    let (fields, last_seen, shows_last_seen) = load(conn, uid).await;
+    let blocked = get_blocked_ids(conn, uid).await;
+
+    let event = |masked: bool| {
+        let last_seen = last_seen.clone().filter(|_| shows_last_seen || !masked);
+        ServerEvent::PresenceUpdate(PresenceUpdate {
+            id: uid.to_owned(),
+            presence: Presence::resolve(&fields, last_seen, masked),
+        })
+        .frame()
+    };
+
+    let masked = event(true);
+    for fid in friend_ids
+        .iter()
+        .filter(|fid| *fid != uid && !blocked.contains(fid))
+    {
+        conn.publish::<_, _, ()>(format!("U.{}", fid), &masked)
+            .await
+            .ok();
+    }
+
+    conn.publish::<_, _, ()>(format!("U.{}", uid), event(false))
+        .await
+        .ok();
+}
+
+/// Like [`broadcast`], for when the friend list isn't at hand: the masked
+/// view goes to `F.{uid}`, which every socket following the user listens on.
+/// Sockets of users they blocked drop it on arrival.
+pub async fn announce(conn: &mut MultiplexedConnection, uid: &str) {
+    let (fields, last_seen, shows_last_seen) = load(conn, uid).await;
+
+    let event = |masked: bool| {
+        let last_seen = last_seen.clone().filter(|_| shows_last_seen || !masked);
+        ServerEvent::PresenceUpdate(PresenceUpdate {
+            id: uid.to_owned(),
+            presence: Presence::resolve(&fields, last_seen, masked),
+        })
+        .frame()
+    };
+
+    conn.publish::<_, _, ()>(format!("F.{}", uid), event(true))
+        .await
+        .ok();
+    conn.publish::<_, _, ()>(format!("U.{}", uid), event(false))
+        .await
+        .ok();
+}
@@ -0,0 +1,141 @@
+use once_cell::sync::Lazy;
+use redis::{aio::MultiplexedConnection, Script};
+use serde_json::{json, Value};
+
+use crate::{utils::log_error, DB_POOL};
+
+use super::presence;
+
+/// Stream of ready-to-send notifications, drained by whatever talks to the
+/// push providers.
+const NOTIFICATIONS_STREAM: &str = "push:notifications";
+
+const NOTIFICATIONS_MAXLEN: usize = 100_000;
+
+/// How long an event is remembered as notified, comfortably longer than it
+/// can take to be redelivered.
+const DEDUPE_TTL: u64 = 24 * 60 * 60;
+
+/// Queues a notification unless one went out for this event and user already.
+static NOTIFY_ONCE: Lazy<Script> = Lazy::new(|| {
+    Script::new(
+        r#"
+        if not redis.call('SET', KEYS[1], '1', 'NX', 'EX', ARGV[1]) then
+          return 0
+        end
+        redis.call('XADD', KEYS[2], 'MAXLEN', '~', ARGV[2], '*',
+          'user_id', ARGV[3], 'tokens', ARGV[4], 'event', ARGV[5])
+        return 1
+        "#,
+    )
+});
+
+/// Whether an event is worth a notification at all.
+fn notifies(event: &Value) -> bool {
+    match event["type"].as_str().unwrap_or_default() {
+        // System messages about members coming and going don't ping anyone.
+        "MESSAGE_CREATE" => event["data"]["type"]
+            .as_str()
+            .is_none_or(|kind| kind == "DEFAULT"),
+        // Only the side receiving a friend request.
+        "RELATIONSHIP_ADD" => event["data"]["incoming"].as_bool() == Some(true),
+        _ => false,
+    }
+}
+
+/// Users an event on `topic` should notify. Channel members are left out
+/// while muted, by their `notify` setting, and when they blocked the author.
+async fn recipients(topic: &str, event: &Value) -> Result<Vec<String>, sqlx::Error> {
+    match topic.split_once('.') {
+        Some(("U", uid)) => Ok(vec![uid.to_owned()]),
+        Some(("C", channel_id)) => {
+            let pool = DB_POOL.get().unwrap();
+            let author = event["data"]["author_id"].as_str().unwrap_or_default();
+            let content = event["data"]["content"].as_str().unwrap_or_default();
+
+            sqlx::query_scalar(
+                r#"SELECT "user_id" FROM "channel_members"
+                WHERE "channel_id" = $1 AND "user_id" != $2
+                  AND ("muted_until" IS NULL OR "muted_until" <= now())
+                  AND NOT EXISTS (
+                    SELECT FROM "user_blocks"
+                    WHERE "blocker_id" = "channel_members"."user_id" AND "blocked_id" = $2
+                  )
+                  AND (
+                    "notify" = 'ALL'
+                    OR ("notify" = 'MENTIONS' AND strpos($3, '<@' || "user_id" || '>') > 0)
+                  )"#,
+            )
+            .bind(channel_id)
+            .bind(author)
+            .bind(content)
+            .fetch_all(pool)
+            .await
+        }
+        _ => Ok(vec![]),
+    }
+}
+
+/// Turns a relayed event into notifications for everyone it concerns who
+/// hasn't asked for quiet. Safe to call more than once for the same
+/// `event_id`. Returns `false` when it should be retried.
+pub async fn handle(
+    conn: &mut MultiplexedConnection,
+    event_id: &str,
+    topic: &str,
+    event: &Value,
+) -> bool {
+    if !notifies(event) {
+        return true;
+    }
+
+    let recipients = match recipients(topic, event).await {
+        Ok(recipients) => recipients,
+        Err(err) => {
+            log_error("Push_RECIPIENTS", Some(&err));
+            return false;
+        }
+    };
+
+    let pool = DB_POOL.get().unwrap();
+
+    for uid in recipients {
+        if presence::suppresses_push(conn, &uid).await {
+            continue;
+        }
+
+        let tokens: Vec<String> =
+            match sqlx::query_scalar(r#"SELECT "token" FROM "push_tokens" WHERE "user_id" = $1"#)
+                .bind(&uid)
+                .fetch_all(pool)
+                .await
+            {
+                Ok(tokens) => tokens,
+                Err(err) => {
+                    log_error("Push_TOKENS", Some(&err));
+                    return false;
+                }
+            };
+
+        if tokens.is_empty() {
+            continue;
+        }
+
+        let queued = NOTIFY_ONCE
+            .key(format!("push:sent:{}:{}", event_id, uid))
+            .key(NOTIFICATIONS_STREAM)
+            .arg(DEDUPE_TTL)
+            .arg(NOTIFICATIONS_MAXLEN)
+            .arg(&uid)
+            .arg(json!(tokens).to_string())
+            .arg(event.to_string())
+            .invoke_async::<i64>(conn)
+            .await;
+
+        if queued.is_err() {
+            return false;
+        }
+    }
+
+    true
+}
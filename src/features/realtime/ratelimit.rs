@@ -0,0 +1,186 @@
+use std::{collections::HashMap, time::Duration};
+
+use once_cell::sync::Lazy;
+use redis::{aio::MultiplexedConnection, Script};
+use tokio::time::Instant;
+
+/// Budget shared by everything a user sends, valid or not.
+pub const ALL_EVENTS: &str = "*";
+
+/// Close code for clients that keep ignoring their limits.
+pub const POLICY_VIOLATION_CODE: u16 = 1008;
+
+/// Limits a connection may run into within [`STRIKE_WINDOW`] before it's closed.
+const MAX_STRIKES: u32 = 10;
+
+const STRIKE_WINDOW: Duration = Duration::from_secs(60);
+
+/// `EVENT=burst/seconds` pairs: up to `burst` events at once, refilled
+/// evenly over `seconds`. Shared by every session of a user. REST endpoints
+/// with a budget of their own, like `USER_SEARCH`, are listed here too.
+const DEFAULT_BUDGETS: &str =
+    "*=120/60,PING=30/60,PUSH_TOKEN=5/300,PRESENCE_SET=20/60,USER_SEARCH=30/60";
+
+/// Defaults overridden by whatever `WS_RATE_LIMITS` sets, in the same format.
+static BUDGETS: Lazy<HashMap<String, Budget>> = Lazy::new(|| {
+    let mut budgets = parse_budgets(DEFAULT_BUDGETS);
+    if let Ok(custom) = std::env::var("WS_RATE_LIMITS") {
+        budgets.extend(parse_budgets(&custom));
+    }
+    budgets
+});
+
+/// Token bucket refilled from the Redis clock, so every node agrees on it.
+/// Returns 0 when a token was taken, or the milliseconds until one is back.
+static TAKE: Lazy<Script> = Lazy::new(|| {
+    Script::new(
+        r#"
+        local burst = tonumber(ARGV[1])
+        local window = tonumber(ARGV[2])
+        local time = redis.call('TIME')
+        local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+        local bucket = redis.call('HMGET', KEYS[1], 'tokens', 'ts')
+        local tokens = tonumber(bucket[1]) or burst
+        local ts = tonumber(bucket[2]) or now
+        tokens = math.min(burst, tokens + (now - ts) * burst / window)
+        if tokens < 1 then
+          return math.ceil((1 - tokens) * window / burst)
+        end
+        redis.call('HSET', KEYS[1], 'tokens', tostring(tokens - 1), 'ts', now)
+        redis.call('PEXPIRE', KEYS[1], window)
+        return 0
+        "#,
+    )
+});
+
+#[derive(Clone, Copy)]
+struct Budget {
+    burst: u32,
+    per: Duration,
+}
+
+fn parse_budgets(spec: &str) -> HashMap<String, Budget> {
+    spec.split(',')
+        .filter_map(|entry| {
+            let (event, budget) = entry.trim().split_once('=')?;
+            let event = event.trim();
+            let (burst, secs) = budget.split_once('/')?;
+            let budget = Budget {
+                burst: burst.trim().parse().ok().filter(|burst| *burst > 0)?,
+                per: Duration::from_secs(secs.trim().parse().ok().filter(|secs| *secs > 0)?),
+            };
+            (!event.is_empty()).then(|| (event.to_owned(), budget))
+        })
+        .collect()
+}
+
+fn bucket_key(uid: &str, event: &str) -> String {
+    format!("ratelimit:{}:{}", uid, event)
+}
+
+/// A connection's view of its user's budgets, which also tracks how often
+/// the client ran out of them.
+pub struct RateLimiter {
+    uid: String,
+    strikes: u32,
+    window_start: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(uid: &str) -> Self {
+        RateLimiter {
+            uid: uid.to_owned(),
+            strikes: 0,
+            window_start: Instant::now(),
+        }
+    }
+
+    /// Takes a token from the budget for `event`, or returns how long to wait
+    /// when it's empty. Events without a budget, and Redis errors, pass.
+    pub async fn check(
+        &mut self,
+        conn: &mut MultiplexedConnection,
+        event: &str,
+    ) -> Result<(), Duration> {
+        let Some(budget) = BUDGETS.get(event) else {
+            return Ok(());
+        };
+
+        let retry_after: u64 = TAKE
+            .key(bucket_key(&self.uid, event))
+            .arg(budget.burst)
+            .arg(budget.per.as_millis() as u64)
+            .invoke_async(conn)
+            .await
+            .unwrap_or(0);
+
+        if retry_after == 0 {
+            return Ok(());
+        }
+
+        if self.window_start.elapsed() > STRIKE_WINDOW {
+            self.strikes = 0;
+            self.window_start = Instant::now();
+        }
+        self.strikes += 1;
+
+        Err(Duration::from_millis(retry_after))
+    }
+
+    /// Whether the client kept going past its limits and should be disconnected.
+    pub fn is_abusive(&self) -> bool {
+        self.strikes >= MAX_STRIKES
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_every_default() {
+        let budgets = parse_budgets(DEFAULT_BUDGETS);
+        assert_eq!(budgets.len(), DEFAULT_BUDGETS.split(',').count());
+
+        let all = budgets[ALL_EVENTS];
+        assert_eq!(all.burst, 120);
+        assert_eq!(all.per, Duration::from_secs(60));
+    }
+
+    #[test]
+    fn trims_whitespace() {
+        let budgets = parse_budgets(" PING = 3 / 10 ,PUSH_TOKEN=1/5");
+        assert_eq!(budgets["PING"].burst, 3);
+        assert_eq!(budgets["PING"].per, Duration::from_secs(10));
+        assert_eq!(budgets["PUSH_TOKEN"].burst, 1);
+    }
+
+    #[test]
+    fn skips_malformed_entries() {
+        for spec in [
+            "",
+            "PING",
+            "PING=",
+            "PING=5",
+            "PING=/60",
+            "PING=5/",
+            "=5/60",
+            "PING=five/60",
+            "PING=5/sixty",
+            "PING=-5/60",
+            "PING=5/-60",
+            "PING=0/60",
+            "PING=5/0",
+            "PING=5/60/10",
+        ] {
+            assert!(parse_budgets(spec).is_empty(), "{:?} was accepted", spec);
+        }
+    }
+
+    #[test]
+    fn keeps_valid_entries_next_to_malformed_ones() {
+        let budgets = parse_budgets("PING=oops,PRESENCE_SET=20/60,,*=0/60");
+        assert_eq!(budgets.len(), 1);
+        assert_eq!(budgets["PRESENCE_SET"].burst, 20);
+    }
+}
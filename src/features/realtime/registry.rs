@@ -0,0 +1,340 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use once_cell::sync::Lazy;
+use redis::{aio::MultiplexedConnection, AsyncCommands, Script};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+use crate::RD_POOL;
+
+use super::{delivery::NODE_ID, hub, outbox::EVENTS_STREAM, presence};
+
+/// How long a node is presumed alive after its last heartbeat.
+const LEASE: Duration = Duration::from_secs(30);
+
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Close code for sockets an admin disconnected.
+pub const KICKED_CODE: u16 = 4010;
+
+/// Live nodes, scored by when their lease runs out in milliseconds.
+const NODES_KEY: &str = "nodes";
+
+/// Something a connection is told to do from outside its socket.
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum Control {
+    /// Close for good, on an admin's request.
+    Kick,
+    /// Close and have the client come back, for when this node was written
+    /// off while it was still up.
+    Reconnect,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Command {
+    connection_id: String,
+    control: Control,
+}
+
+/// Connections on this node, by id.
+static LOCAL: Lazy<Mutex<HashMap<String, mpsc::UnboundedSender<Control>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Drops one connection of a dead node, returning its user unless someone
+/// else got to it first.
+static REAP_CONNECTION: Lazy<Script> = Lazy::new(|| {
+    Script::new(
+        r#"
+        if redis.call('SREM', KEYS[1], ARGV[1]) == 0 then
+          return false
+        end
+        local key = 'connection:' .. ARGV[1]
+        local fields = redis.call('HMGET', key, 'user_id', 'session_id')
+        redis.call('DEL', key)
+        if not fields[1] then
+          return false
+        end
+        redis.call('SREM', 'user:' .. fields[1] .. ':connections', ARGV[1])
+        if fields[2] then
+          redis.call('DEL', 'user:session:' .. fields[2] .. ':subscriptions')
+        end
+        return fields[1]
+        "#,
+    )
+});
+
+fn node_connections_key(node: &str) -> String {
+    format!("node:{}:connections", node)
+}
+
+fn user_connections_key(uid: &str) -> String {
+    format!("user:{}:connections", uid)
+}
+
+fn connection_key(connection_id: &str) -> String {
+    format!("connection:{}", connection_id)
+}
+
+/// Topic a node takes commands for its connections on.
+fn control_topic(node: &str) -> String {
+    format!("N.{}", node)
+}
+
+/// Milliseconds since the epoch by the Redis clock, which every node shares.
+async fn now(conn: &mut MultiplexedConnection) -> u64 {
+    match redis::cmd("TIME").query_async::<(u64, u64)>(conn).await {
+        Ok((secs, micros)) => secs * 1000 + micros / 1000,
+        Err(_) => SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64,
+    }
+}
+
+/// Records a socket of `uid` as held by this node. The receiver gets what
+/// admins or the registry want done with it.
+pub async fn register(
+    conn: &mut MultiplexedConnection,
+    connection_id: &str,
+    session_id: &str,
+    uid: &str,
+) -> mpsc::UnboundedReceiver<Control> {
+    let (tx, rx) = mpsc::unbounded_channel();
+    LOCAL.lock().unwrap().insert(connection_id.to_owned(), tx);
+
+    redis::pipe()
+        .atomic()
+        .hset_multiple(
+            connection_key(connection_id),
+            &[
+                ("user_id", uid),
+                ("session_id", session_id),
+                ("node", NODE_ID.as_str()),
+            ],
+        )
+        .ignore()
+        .sadd(node_connections_key(&NODE_ID), connection_id)
+        .ignore()
+        .sadd(user_connections_key(uid), connection_id)
+        .ignore()
+        .query_async::<()>(conn)
+        .await
+        .ok();
+
+    rx
+}
+
+/// Forgets a socket. Returns `false` when it had already been reaped along
+/// with this node, in which case its presence was released then.
+pub async fn unregister(conn: &mut MultiplexedConnection, connection_id: &str, uid: &str) -> bool {
+    LOCAL.lock().unwrap().remove(connection_id);
+
+    let (removed,): (i64,) = redis::pipe()
+        .atomic()
+        .srem(node_connections_key(&NODE_ID), connection_id)
+        .del(connection_key(connection_id))
+        .ignore()
+        .srem(user_connections_key(uid), connection_id)
+        .ignore()
+        .query_async(conn)
+        .await
+        .unwrap_or((1,));
+
+    removed == 1
+}
+
+/// Disconnects every socket of `uid`, whichever node holds it. Returns how
+/// many there were.
+pub async fn kick(conn: &mut MultiplexedConnection, uid: &str) -> usize {
+    let connection_ids: Vec<String> = conn
+        .smembers(user_connections_key(uid))
+        .await
+        .unwrap_or_default();
+
+    let mut kicked = 0;
+    for connection_id in connection_ids {
+        let node: Option<String> = conn
+            .hget(connection_key(&connection_id), "node")
+            .await
+            .unwrap_or(None);
+        let Some(node) = node else {
+            continue;
+        };
+
+        let command = Command {
+            connection_id,
+            control: Control::Kick,
+        };
+        let published = conn
+            .publish::<_, _, ()>(
+                control_topic(&node),
+                serde_json::to_string(&command).unwrap(),
+            )
+            .await;
+
+        if published.is_ok() {
+            kicked += 1;
+        }
+    }
+
+    kicked
+}
+
+/// Expires every connection `node` had: their users lose a connection each
+/// and are announced offline if that was the last of them.
+async fn reap(conn: &mut MultiplexedConnection, node: &str) {
+    let connection_ids: Vec<String> = conn
+        .smembers(node_connections_key(node))
+        .await
+        .unwrap_or_default();
+
+    let mut released: HashMap<String, i64> = HashMap::new();
+    for connection_id in &connection_ids {
+        let uid: Option<String> = REAP_CONNECTION
+            .key(node_connections_key(node))
+            .arg(connection_id)
+            .invoke_async(conn)
+            .await
+            .unwrap_or(None);
+
+        if let Some(uid) = uid {
+            *released.entry(uid).or_default() += 1;
+        }
+    }
+
+    if !connection_ids.is_empty() {
+        println!(
+            "Expired {} connections of node {}",
+            connection_ids.len(),
+            node
+        );
+    }
+
+    // Each release waits out the grace period, which must not hold back
+    // renewing this node's own lease.
+    for (uid, connections) in released {
+        let mut conn = conn.clone();
+        tokio::spawn(async move {
+            if presence::disconnect(&mut conn, &uid, connections).await {
+                presence::announce(&mut conn, &uid).await;
+            }
+        });
+    }
+}
+
+/// Renews this node's lease and reaps nodes whose lease ran out. Returns
+/// `false` when Redis is unreachable.
+async fn heartbeat(conn: &mut MultiplexedConnection) -> bool {
+    let now = now(conn).await;
+
+    let Ok(added) = conn
+        .zadd::<_, _, _, i64>(NODES_KEY, &*NODE_ID, now + LEASE.as_millis() as u64)
+        .await
+    else {
+        return false;
+    };
+
+    // Another node took this one for dead and released its connections, so
+    // the sockets still open here are no longer accounted for.
+    if added == 1 {
+        println!(
+            "Lease of node {} had expired, reconnecting its sockets",
+            *NODE_ID
+        );
+        for tx in LOCAL.lock().unwrap().values() {
+            tx.send(Control::Reconnect).ok();
+        }
+    }
+
+    let expired: Vec<String> = conn
+        .zrangebyscore(NODES_KEY, "-inf", now)
+        .await
+        .unwrap_or_default();
+
+    for node in expired {
+        // Whoever removes it does the reaping.
+        let removed: i64 = conn.zrem(NODES_KEY, &node).await.unwrap_or(0);
+        if removed == 0 {
+            continue;
+        }
+
+        reap(conn, &node).await;
+
+        // Nothing reads the dead node's group anymore.
+        conn.xgroup_destroy::<_, _, ()>(EVENTS_STREAM, format!("node:{}", node))
+            .await
+            .ok();
+    }
+
+    true
+}
+
+/// Passes commands published for this node on to its connections, starting
+/// over should the hub drop the subscriber for falling behind.
+async fn listen() {
+    loop {
+        let mut subscriber = hub::subscriber();
+        subscriber.subscribe(&[control_topic(&NODE_ID)]).await;
+
+        while let Some(msg) = subscriber.recv().await {
+            let Ok(command) = serde_json::from_slice::<Command>(&msg.payload) else {
+                continue;
+            };
+
+            if let Some(tx) = LOCAL.lock().unwrap().get(&command.connection_id) {
+                tx.send(command.control).ok();
+            }
+        }
+    }
+}
+
+/// Joins the registry, starting with a clean slate for connections a previous
+/// run under the same `NODE_ID` left behind, then keeps the lease up.
+pub async fn join() {
+    let client = RD_POOL.get().unwrap().clone();
+
+    if let Ok(mut conn) = client.get_multiplexed_async_connection().await {
+        reap(&mut conn, &NODE_ID).await;
+
+        let expires = now(&mut conn).await + LEASE.as_millis() as u64;
+        conn.zadd::<_, _, _, ()>(NODES_KEY, &*NODE_ID, expires)
+            .await
+            .ok();
+    }
+
+    tokio::spawn(listen());
+
+    tokio::spawn(async move {
+        let mut conn = None;
+        let mut interval = tokio::time::interval(HEARTBEAT_INTERVAL);
+        interval.reset();
+
+        loop {
+            interval.tick().await;
+
+            if conn.is_none() {
+                conn = client.get_multiplexed_async_connection().await.ok();
+            }
+            if let Some(redis) = &mut conn {
+                if !heartbeat(redis).await {
+                    conn = None;
+                }
+            }
+        }
+    });
+}
+
+/// Gives up the lease on shutdown, once sockets have unregistered.
+pub async fn leave() {
+    let client = RD_POOL.get().unwrap();
+
+    if let Ok(mut conn) = client.get_multiplexed_async_connection().await {
+        reap(&mut conn, &NODE_ID).await;
+        conn.zrem::<_, _, ()>(NODES_KEY, &*NODE_ID).await.ok();
+    }
+}
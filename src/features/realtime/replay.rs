@@ -0,0 +1,330 @@
+use std::{collections::VecDeque, time::Duration};
+
+use cuid::cuid1;
+use once_cell::sync::Lazy;
+use redis::{aio::MultiplexedConnection, AsyncCommands, Script};
+use serde_json::{json, Value};
+
+use super::subscriptions::Subscriptions;
+
+/// How long a dropped connection can be resumed for. Events keep being
+/// buffered for this long after the socket closes.
+pub const RESUME_WINDOW: Duration = Duration::from_secs(120);
+
+/// Close code for a connection whose events can no longer be sequenced, so
+/// the client reconnects and RESUMEs.
+pub const RESYNC_CODE: u16 = 4011;
+
+/// Upper bound on buffered events per connection; older ones are trimmed.
+const MAX_BUFFERED: usize = 1000;
+
+/// Bumps the sequence number, unless a newer connection took over the log.
+static NEXT_SEQ: Lazy<Script> = Lazy::new(|| {
+    Script::new(
+        r#"
+        if redis.call('HGET', KEYS[1], 'gen') ~= ARGV[1] then
+          return false
+        end
+        return redis.call('HINCRBY', KEYS[1], 'seq', 1)
+        "#,
+    )
+});
+
+/// Hands the log over to a resuming connection and returns what it missed,
+/// or nothing if the buffer expired, was trimmed or belongs to someone else.
+static TAKE_OVER: Lazy<Script> = Lazy::new(|| {
+    Script::new(
+        r#"
+        if redis.call('HGET', KEYS[1], 'user_id') ~= ARGV[1] then
+          return false
+        end
+        local current = tonumber(redis.call('HGET', KEYS[1], 'seq'))
+        local last = tonumber(ARGV[2])
+        if last == nil or last > current then
+          return false
+        end
+        local entries = {}
+        if last < current then
+          entries = redis.call('XRANGE', KEYS[2], tostring(last + 1) .. '-0', '+')
+          if #entries ~= current - last then
+            return false
+          end
+        end
+        local gen = redis.call('HINCRBY', KEYS[1], 'gen', 1)
+        redis.call('EXPIRE', KEYS[1], ARGV[3])
+        redis.call('EXPIRE', KEYS[2], ARGV[3])
+        local out = { gen, current }
+        for _, entry in ipairs(entries) do
+          table.insert(out, entry[2][2])
+        end
+        return out
+        "#,
+    )
+});
+
+fn meta_key(id: &str) -> String {
+    format!("replay:{}:meta", id)
+}
+
+fn stream_key(id: &str) -> String {
+    format!("replay:{}", id)
+}
+
+/// A connection's handle on its replay log.
+///
+/// Only the holder of the latest generation may append, so a stale socket
+/// still lingering after a RESUME stops writing as soon as it's superseded.
+#[derive(Clone)]
+pub struct Replay {
+    pub id: String,
+    generation: u64,
+}
+
+pub struct Resumed {
+    pub replay: Replay,
+    pub seq: u64,
+    /// Already sequenced frames, oldest first.
+    pub missed: Vec<String>,
+}
+
+/// The events a RESUME replayed, to tell apart the ones still queued on the
+/// new subscriber, which started listening before the old connection stopped
+/// recording.
+#[derive(Default)]
+pub struct Replayed {
+    events: VecDeque<Value>,
+}
+
+impl Replayed {
+    pub fn new(missed: &[String]) -> Self {
+        let events = missed
+            .iter()
+            .filter_map(|frame| serde_json::from_str::<Value>(frame).ok())
+            .map(|mut event| {
+                if let Some(event) = event.as_object_mut() {
+                    event.remove("seq");
+                }
+                event
+            })
+            .collect();
+
+        Replayed { events }
+    }
+
+    /// Whether `event` already went out with the replay. Both see events in
+    /// publishing order, so the first one that didn't ends the overlap.
+    pub fn take(&mut self, event: &Value) -> bool {
+        match self.events.iter().position(|replayed| replayed == event) {
+            Some(i) => {
+                self.events.drain(..=i);
+                true
+            }
+            None => {
+                self.events.clear();
+                false
+            }
+        }
+    }
+}
+
+impl Replay {
+    /// Starts a fresh log for a new connection of `uid`.
+    pub async fn open(
+        conn: &mut MultiplexedConnection,
+        uid: &str,
+        subscriptions: &Subscriptions,
+    ) -> Self {
+        let id = cuid1().unwrap();
+        let key = meta_key(&id);
+
+        redis::pipe()
+            .atomic()
+            .hset_multiple(
+                &key,
+                &[
+                    ("user_id", uid.to_owned()),
+                    ("seq", "0".to_owned()),
+                    ("gen", "1".to_owned()),
+                    (
+                        "subscriptions",
+                        serde_json::to_string(subscriptions).unwrap(),
+                    ),
+                ],
+            )
+            .ignore()
+            .expire(&key, RESUME_WINDOW.as_secs() as i64)
+            .ignore()
+            .query_async::<()>(conn)
+            .await
+            .ok();
+
+        Replay { id, generation: 1 }
+    }
+
+    /// What the connection behind log `id` was subscribed to, as long as the
+    /// log exists and belongs to `uid`.
+    pub async fn subscriptions(
+        conn: &mut MultiplexedConnection,
+        uid: &str,
+        id: &str,
+    ) -> Option<Subscriptions> {
+        let (owner, subscriptions): (Option<String>, Option<String>) = redis::cmd("HMGET")
+            .arg(meta_key(id))
+            .arg("user_id")
+            .arg("subscriptions")
+            .query_async(conn)
+            .await
+            .ok()?;
+
+        if owner.as_deref() != Some(uid) {
+            return None;
+        }
+
+        serde_json::from_str(&subscriptions?).ok()
+    }
+
+    /// Keeps the subscriptions a RESUME restores in step with the connection.
+    pub async fn save_subscriptions(
+        &self,
+        conn: &mut MultiplexedConnection,
+        subscriptions: &Subscriptions,
+    ) {
+        let key = meta_key(&self.id);
+        redis::pipe()
+            .hset(
+                &key,
+                "subscriptions",
+                serde_json::to_string(subscriptions).unwrap(),
+            )
+            .ignore()
+            .expire(&key, RESUME_WINDOW.as_secs() as i64)
+            .ignore()
+            .query_async::<()>(conn)
+            .await
+            .ok();
+    }
+
+    /// Takes over the log `id` of `uid` if every event after `seq` is still buffered.
+    pub async fn resume(
+        conn: &mut MultiplexedConnection,
+        uid: &str,
+        id: &str,
+        seq: u64,
+    ) -> Option<Resumed> {
+        let reply: Option<Vec<String>> = TAKE_OVER
+            .key(meta_key(id))
+            .key(stream_key(id))
+            .arg(uid)
+            .arg(seq)
+            .arg(RESUME_WINDOW.as_secs())
+            .invoke_async(conn)
+            .await
+            .ok()?;
+
+        let mut reply = reply?.into_iter();
+        let generation = reply.next()?.parse().ok()?;
+        let seq = reply.next()?.parse().ok()?;
+
+        Some(Resumed {
+            replay: Replay {
+                id: id.to_owned(),
+                generation,
+            },
+            seq,
+            missed: reply.collect(),
+        })
+    }
+
+    /// Drops the log, so the next RESUME falls back to a fresh `INIT`.
+    pub async fn discard(&self, conn: &mut MultiplexedConnection) {
+        conn.del::<_, ()>(&[meta_key(&self.id), stream_key(&self.id)])
+            .await
+            .ok();
+    }
+
+    /// Assigns the next sequence number to `event`, buffers it and returns the
+    /// frame to send. `None` means a newer connection took over.
+    pub async fn record(
+        &self,
+        conn: &mut MultiplexedConnection,
+        mut event: Value,
+    ) -> Option<String> {
+        let seq: Option<u64> = NEXT_SEQ
+            .key(meta_key(&self.id))
+            .arg(self.generation)
+            .invoke_async(conn)
+            .await
+            .ok()?;
+        let seq = seq?;
+
+        event["seq"] = json!(seq);
+        let frame = event.to_string();
+
+        let ttl = RESUME_WINDOW.as_secs() as i64;
+        let stream = stream_key(&self.id);
+        redis::pipe()
+            .cmd("XADD")
+            .arg(&stream)
+            .arg("MAXLEN")
+            .arg("~")
+            .arg(MAX_BUFFERED)
+            .arg(format!("{}-0", seq))
+            .arg("frame")
+            .arg(&frame)
+            .ignore()
+            .expire(&stream, ttl)
+            .ignore()
+            .expire(meta_key(&self.id), ttl)
+            .ignore()
+            .query_async::<()>(conn)
+            .await
+            .ok();
+
+        Some(frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frames(events: &[Value]) -> Vec<String> {
+        events
+            .iter()
+            .zip(1..)
+            .map(|(event, seq)| {
+                let mut event = event.clone();
+                event["seq"] = json!(seq);
+                event.to_string()
+            })
+            .collect()
+    }
+
+    fn event(id: &str) -> Value {
+        json!({ "type": "MESSAGE_CREATE", "data": { "id": id } })
+    }
+
+    #[test]
+    fn takes_events_recorded_before_the_take_over() {
+        let mut replayed = Replayed::new(&frames(&[event("a"), event("b"), event("c")]));
+
+        assert!(replayed.take(&event("b")));
+        assert!(replayed.take(&event("c")));
+        assert!(!replayed.take(&event("d")));
+    }
+
+    #[test]
+    fn stops_at_the_first_new_event() {
+        let mut replayed = Replayed::new(&frames(&[event("a"), event("b")]));
+
+        assert!(!replayed.take(&event("c")));
+        assert!(!replayed.take(&event("a")));
+    }
+
+    #[test]
+    fn takes_nothing_after_an_init() {
+        let mut replayed = Replayed::default();
+
+        assert!(!replayed.take(&event("a")));
+    }
+}
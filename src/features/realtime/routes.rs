@@ -1,268 +1,690 @@
 // use  axum
 
-use std::{io::Write, sync::Arc};
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 use axum::{
+    body::Bytes,
     extract::{
-        ws::{Message, WebSocket},
-        WebSocketUpgrade,
+        ws::{CloseFrame, Message},
+        Query,
     },
-    response::Response,
-    routing::any,
-    Json, Router,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::{any, get},
+    Router,
 };
 
 use cuid::cuid1;
-use flate2::{
-    write::{DeflateDecoder, DeflateEncoder},
-    Compression,
-};
-use futures::{future::join_all, StreamExt};
-use redis::{AsyncCommands, ToRedisArgs};
+use futures::future::join_all;
+use redis::{aio::MultiplexedConnection, AsyncCommands, ToRedisArgs};
 use serde::Deserialize;
 use serde_json::{json, Value};
-use tokio::sync::Mutex;
+use tokio::{sync::oneshot, time::Instant};
 
 use crate::{
-    database::sql::get_initial_user::get_initial_user, middlewares::auth::AuthUser,
-    utils::log_error, DB_POOL, RD_POOL,
+    database::sql::{blocks::get_blocked_ids, get_initial_user::get_initial_user},
+    middlewares::auth::AuthUser,
+    utils::log_error,
+    DB_POOL, RD_POOL,
 };
 
-use super::events::RealTimeEvents;
+use super::{
+    codec::{Codec, Compress, Encoding, ZSTD_DICT},
+    events::{self, ClientEvent, ErrorCode, ErrorData, InitData, PresenceUpdate, ServerEvent},
+    hub::{self, Subscriber},
+    outbound::{Outbound, Priority, SLOW_CONSUMER_CODE},
+    presence,
+    ratelimit::{self, RateLimiter},
+    registry::{self, Control},
+    replay::{Replay, Replayed, RESUME_WINDOW, RESYNC_CODE},
+    shutdown,
+    subscriptions::{Changes, Subscriptions},
+    transport::{WebSocket, WebSocketUpgrade},
+};
+
+/// How often clients are expected to `PING`, announced to them in `INIT`.
+/// The server also sends a WebSocket ping at this interval.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Silence after which a connection is considered dead and closed.
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(45);
+
+/// Close code sent when the heartbeat deadline passes.
+const HEARTBEAT_TIMEOUT_CODE: u16 = 4009;
+
+/// How long the writer gets to flush its queue once the connection ends.
+const WRITER_DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
 
 pub fn routes() -> Router {
-    Router::new().route("/", any(handler))
+    Router::new()
+        .route("/", any(handler))
+        .route("/zstd-dict", get(zstd_dict))
 }
 
-async fn handler(ws: WebSocketUpgrade, auth_user: AuthUser) -> Response {
-    ws.on_upgrade(|socket| handle_socket(socket, auth_user))
+#[derive(Deserialize)]
+struct ConnectParams {
+    /// `resume_id` from a previous `INIT`, to pick up where that connection left off.
+    resume: Option<String>,
+    /// Last `seq` the client processed on that connection.
+    seq: Option<u64>,
+    #[serde(default)]
+    encoding: Encoding,
+    #[serde(default)]
+    compress: Compress,
 }
 
-fn compress(data: String) -> Vec<u8> {
-    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+async fn zstd_dict() -> Response {
+    match &*ZSTD_DICT {
+        Some(dict) => dict.clone().into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
 
-    encoder.write_all(&data.as_bytes()).unwrap();
-    let ret_vec = encoder.finish().unwrap();
+async fn handler(
+    ws: WebSocketUpgrade,
+    auth_user: AuthUser,
+    Query(params): Query<ConnectParams>,
+) -> Response {
+    if shutdown::is_stopping() {
+        return StatusCode::SERVICE_UNAVAILABLE.into_response();
+    }
 
-    ret_vec
+    ws.on_upgrade(|socket| handle_socket(socket, auth_user, params))
 }
-fn compress_msg(data: String) -> Message {
-    Message::binary(compress(data))
+
+/// Everything in `INIT` except the replay log, which is opened once the
+/// caller knows whose events it follows.
+async fn initial_payload(
+    auth_user: &AuthUser,
+    session_id: &str,
+    conn: &MultiplexedConnection,
+) -> InitData {
+    let mut user_data = get_initial_user(&auth_user.me).await;
+
+    let mut presences = serde_json::Map::new();
+
+    let fetch_tasks = user_data
+        .get("users")
+        .unwrap()
+        .as_object()
+        .unwrap()
+        .iter()
+        .map(|(friend_id, _)| {
+            let mut conn = conn.clone();
+            let fid = friend_id.clone();
+            async move {
+                let presence = presence::get_for(&mut conn, &fid, &auth_user.me).await;
+                (fid, presence)
+            }
+        });
+
+    let results = join_all(fetch_tasks).await;
+
+    for (fid, presence) in results {
+        presences.insert(fid, json!(presence));
+    }
+
+    InitData {
+        me: user_data["me"].take(),
+        channels: user_data["channels"].take(),
+        relationships: user_data["relationships"].take(),
+        users: user_data["users"].take(),
+        presence: presences,
+        session: session_id.to_owned(),
+        push_token: json!(&auth_user.push_token),
+        heartbeat_interval: HEARTBEAT_INTERVAL.as_millis() as u64,
+        resume_id: String::new(),
+        seq: 0,
+    }
 }
 
-#[derive(Deserialize)]
-struct InEvent {
-    #[serde(rename = "type")]
-    kind: String,
-    data: Value,
+async fn subscribe(subscriber: &mut Subscriber, subscriptions: &Subscriptions) -> Vec<String> {
+    let topics = subscriptions.topics();
+    subscriber.subscribe(&topics).await;
+    topics
 }
 
-fn decompress(msg: Message) -> Result<InEvent, String> {
-    let d_bytes = msg.into_data();
-    let mut decoder = DeflateDecoder::new(Vec::new());
-    decoder
-        .write_all(&d_bytes)
-        .map_err(|_| "Decompression failed")?;
-    let json_b = decoder.finish().map_err(|_| "Finish failed")?;
-    serde_json::from_slice(&json_b).map_err(|_| "Invalid Payload".into())
+/// Brings the hub subscriber, the replay log and, while the socket is
+/// still open, the session's subscription set in line with `changes`.
+async fn update_subscriptions(
+    subscriber: &mut Subscriber,
+    conn: &mut MultiplexedConnection,
+    replay: &Replay,
+    subscriptions: &Subscriptions,
+    changes: &Changes,
+    session_key: Option<&str>,
+) {
+    subscriber.subscribe(&changes.added).await;
+    subscriber.unsubscribe(&changes.removed);
+
+    replay.save_subscriptions(conn, subscriptions).await;
+
+    if let Some(key) = session_key {
+        let mut pipe = redis::pipe();
+        if !changes.added.is_empty() {
+            pipe.sadd(key, &changes.added).ignore();
+        }
+        if !changes.removed.is_empty() {
+            pipe.srem(key, &changes.removed).ignore();
+        }
+        pipe.query_async::<()>(conn).await.ok();
+    }
 }
 
-fn payload(kind: &str, data: Option<&Value>) -> String {
-    Json(json!({
-        "type": kind,
-        "data": data
-    }))
-    .to_string()
+/// Closes a socket whose events couldn't be recorded, rather than leave the
+/// client connected without them. Only the first call sends anything.
+fn resync(outbound: Option<&Outbound>, resyncing: &mut bool) {
+    if std::mem::replace(resyncing, true) {
+        return;
+    }
+    if let Some(outbound) = outbound {
+        let close = Message::Close(Some(CloseFrame {
+            code: RESYNC_CODE,
+            reason: "Resume required".into(),
+        }));
+        outbound.control(close);
+    }
+}
+
+/// Answers an event over its rate limit. Returns `false` when the client has
+/// been at it for long enough to be disconnected instead.
+fn reject(outbound: &Outbound, limiter: &RateLimiter, retry_after: Duration) -> bool {
+    if limiter.is_abusive() {
+        let close = Message::Close(Some(CloseFrame {
+            code: ratelimit::POLICY_VIOLATION_CODE,
+            reason: "Rate limit exceeded".into(),
+        }));
+        outbound.control(close);
+        return false;
+    }
+
+    let err = ErrorData::rate_limited(retry_after);
+    outbound.send(ServerEvent::Error(err).frame(), Priority::Normal)
 }
 
-async fn handle_socket(socket: WebSocket, auth_user: AuthUser) {
-    if let Some(session) = auth_user.session {
-        let socket = Arc::new(Mutex::from(socket));
-        let mut user_data = get_initial_user(&auth_user.me).await;
+async fn handle_socket(socket: WebSocket, auth_user: AuthUser, params: ConnectParams) {
+    if let Some(session) = &auth_user.session {
+        let _guard = shutdown::guard();
+
+        let (mut sink, mut ws_stream) = socket.split();
+        let codec = Codec {
+            encoding: params.encoding,
+            compress: params.compress,
+        };
 
-        let mut presences = serde_json::Map::new();
+        // Clients on the legacy app-level deflate send compressed bytes
+        // already, so permessage-deflate would only burn CPU on them.
+        sink.set_compression(codec.compress == Compress::None);
+        let (outbound, mut writer) = Outbound::spawn(sink, codec.encoder());
+        let user_id = auth_user.user.id.clone();
 
         let client = RD_POOL.get().unwrap().clone();
         let mut conn = client.get_multiplexed_async_connection().await.unwrap();
 
-        let fetch_tasks = user_data
-            .get("users")
-            .unwrap()
-            .as_object()
-            .unwrap()
-            .iter()
-            .map(|(friend_id, _)| {
-                let mut conn = conn.clone();
-                let fid = friend_id.clone();
-                async move {
-                    let presence: String = conn
-                        .hget(format!("user:{}", fid), "presence")
-                        .await
-                        .unwrap_or("OFFLINE".to_owned());
-                    let last_seen: Option<String> = conn
-                        .hget(format!("user-lastSeen:{}", fid), "lastSeen")
-                        .await
-                        .unwrap_or_default();
-                    (fid, presence, last_seen)
+        // Subscribe before building INIT or replaying, so nothing published
+        // in between can slip through.
+        let mut subscriber = hub::subscriber();
+        let mut topics = vec![];
+
+        let mut resumed = None;
+        if let (Some(resume_id), Some(seq)) = (&params.resume, params.seq) {
+            if let Some(subscriptions) = Replay::subscriptions(&mut conn, &user_id, resume_id).await
+            {
+                topics = subscribe(&mut subscriber, &subscriptions).await;
+                resumed = Replay::resume(&mut conn, &user_id, resume_id, seq)
+                    .await
+                    .map(|resumed| (resumed, subscriptions));
+            }
+        }
+
+        let (replay, subscriptions, mut replayed) = match resumed {
+            Some((resumed, subscriptions)) => {
+                let hello = ServerEvent::Resumed {
+                    resume_id: resumed.replay.id.clone(),
+                    seq: resumed.seq,
+                    heartbeat_interval: HEARTBEAT_INTERVAL.as_millis() as u64,
+                };
+
+                if !outbound.send(hello.frame(), Priority::Normal) {
+                    return;
                 }
-            });
 
-        let results = join_all(fetch_tasks).await;
+                let replayed = Replayed::new(&resumed.missed);
+                for frame in resumed.missed {
+                    if !outbound.send(frame, Priority::Normal) {
+                        return;
+                    }
+                }
 
-        for (fid, presence, last_seen) in results {
-            presences.insert(fid, json!({ "presence": presence, "lastSeen": last_seen }));
-        }
+                (resumed.replay, subscriptions, replayed)
+            }
 
-        if let Value::Object(ref mut map) = user_data {
-            map.entry("presence").or_insert(json!(&presences));
-            map.insert("session".to_string(), json!(&session.id));
-            map.insert("push_token".to_string(), json!(&auth_user.push_token));
-        }
+            None => {
+                let mut init = initial_payload(&auth_user, &session.id, &conn).await;
 
-        if socket
-            .lock()
-            .await
-            .send(compress_msg(payload("INIT", Some(&user_data))))
+                let subscriptions =
+                    Subscriptions::new(&user_id, &init.relationships, &init.channels);
+
+                // A failed RESUME may have left topics this INIT no longer needs.
+                let current = subscriptions.topics();
+                let stale: Vec<String> = topics
+                    .into_iter()
+                    .filter(|t| !current.contains(t))
+                    .collect();
+                subscriber.unsubscribe(&stale);
+                topics = subscribe(&mut subscriber, &subscriptions).await;
+
+                let replay = Replay::open(&mut conn, &user_id, &subscriptions).await;
+                init.resume_id = replay.id.clone();
+
+                let init = ServerEvent::Init(Box::new(init));
+                if !outbound.send(init.frame(), Priority::Normal) {
+                    return;
+                }
+
+                (replay, subscriptions, Replayed::default())
+            }
+        };
+
+        let session_key = format!("user:session:{}:subscriptions", session.id);
+        redis::cmd("SADD")
+            .arg(&session_key)
+            .arg(topics.to_redis_args())
+            .query_async::<()>(&mut conn)
             .await
-            .is_err()
-        {
-            return;
+            .ok();
+
+        if presence::connect(&mut conn, &user_id).await {
+            presence::broadcast(&mut conn, &user_id, &subscriptions.users()).await;
         }
 
-        let user_id = auth_user.me.clone();
-        let friend_ids: Vec<String> = user_data
-            .get("users")
-            .unwrap()
-            .as_object()
-            .unwrap()
-            .keys()
-            .cloned()
-            .collect();
+        let connection_id = cuid1().unwrap();
+        let mut control =
+            registry::register(&mut conn, &connection_id, &session.id, &user_id).await;
 
-        let (mut sink, mut stream) = client.get_async_pubsub().await.unwrap().split();
+        // Shared with the forwarder, which keeps it current as friends and
+        // channel members come and go.
+        let subscriptions = Arc::new(Mutex::new(subscriptions));
 
-        let socket_spawn = socket.clone();
-        let sid = session.id.clone();
+        // Tells the forwarder whether the client may still RESUME.
+        let (detach, mut detached) = oneshot::channel::<bool>();
+        let mut outbound_spawn = Some(outbound.clone());
         let mut conn_spawn = conn.clone();
+        let subscriptions_spawn = subscriptions.clone();
+        let user_id_spawn = user_id.clone();
+        let own_topic = format!("U.{}", user_id);
+
+        // Users this one blocked, whose messages are flagged for the client
+        // to hide.
+        let mut blocked: HashSet<String> = get_blocked_ids(&mut conn, &user_id)
+            .await
+            .into_iter()
+            .collect();
+        let session_key_spawn = session_key.clone();
         tokio::spawn(async move {
-            let mut subscriptions: Vec<String> = vec![];
-
-            sink.subscribe(&[format!("U.{}", user_id)]).await.ok();
-
-            subscriptions.push(format!("U.{}", user_id));
-
-            for fid in friend_ids {
-                sink.subscribe(format!("F.{}", fid)).await.ok();
-                subscriptions.push(format!("F.{}", fid));
-
-                if fid != auth_user.user.id {
-                    conn_spawn
-                        .publish::<String, Vec<u8>, ()>(
-                            format!("U.{}", fid),
-                            compress(payload(
-                                "PRESENCE_UPDATE",
-                                Some(&json!({
-                                    "id": &user_id,
-                                    "presence": "ONLINE",
-                                    "lastSeen": null
-                                })),
-                            )),
-                        )
-                        .await
-                        .ok();
-                }
-            }
+            let _guard = shutdown::guard();
+
+            // Once the socket is gone, keep buffering for a while so the
+            // client can RESUME, unless another connection takes over first.
+            let linger = tokio::time::sleep(Duration::MAX);
+            tokio::pin!(linger);
+
+            // Set once the socket was told to RESUME, after which it gets
+            // nothing more.
+            let mut resyncing = false;
+
+            loop {
+                let msg = tokio::select! {
+                    msg = subscriber.recv() => match msg {
+                        Some(msg) => msg,
+                        // The hub dropped it for falling behind, so whatever
+                        // it missed isn't in the log either.
+                        None => {
+                            replay.discard(&mut conn_spawn).await;
+                            if let Some(outbound) = &outbound_spawn {
+                                outbound.control(Message::Close(Some(CloseFrame {
+                                    code: SLOW_CONSUMER_CODE,
+                                    reason: "Too slow to keep up".into(),
+                                })));
+                            }
+                            break;
+                        }
+                    },
 
-            redis::cmd("SADD")
-                .arg(format!("user:session:{}:subscriptions", sid))
-                .arg(subscriptions.to_redis_args())
-                .query_async::<String>(&mut conn)
-                .await
-                .unwrap();
+                    resumable = &mut detached, if outbound_spawn.is_some() => {
+                        if resumable == Ok(false) {
+                            replay.discard(&mut conn_spawn).await;
+                            break;
+                        }
+                        outbound_spawn = None;
+                        linger.as_mut().reset(Instant::now() + RESUME_WINDOW);
+                        continue;
+                    }
 
-            while let Some(msg) = stream.next().await {
-                let payload = msg.get_payload_bytes();
+                    _ = &mut linger, if outbound_spawn.is_none() => break,
 
-                if socket_spawn
-                    .lock()
-                    .await
-                    .send(Message::from(payload))
-                    .await
-                    .is_err()
-                {
-                    return;
+                    // Nothing gets recorded once this node is gone, so a
+                    // RESUME elsewhere would silently skip events. Make the
+                    // client start over with INIT instead.
+                    _ = shutdown::stopping() => {
+                        replay.discard(&mut conn_spawn).await;
+                        break;
+                    }
+                };
+
+                let Ok(mut event) = serde_json::from_slice::<Value>(&msg.payload) else {
+                    continue;
+                };
+
+                let topic = &msg.topic;
+                let kind = event["type"].as_str().unwrap_or_default().to_owned();
+                let priority = Priority::of(&kind);
+
+                match kind.as_str() {
+                    "PRESENCE_UPDATE" => {
+                        if let Some(uid) = topic.strip_prefix("F.") {
+                            if presence::hides_from(&mut conn_spawn, uid, &user_id_spawn).await {
+                                continue;
+                            }
+                        }
+                    }
+                    "BLOCK_ADD" | "BLOCK_REMOVE" if *topic == own_topic => {
+                        if let Some(uid) = event["data"]["user_id"].as_str() {
+                            if kind == "BLOCK_ADD" {
+                                blocked.insert(uid.to_owned());
+                            } else {
+                                blocked.remove(uid);
+                            }
+                        }
+                    }
+                    "MESSAGE_CREATE" => {
+                        let author = event["data"]["author_id"].as_str().unwrap_or_default();
+                        if blocked.contains(author) {
+                            event["data"]["blocked"] = Value::Bool(true);
+                        }
+                    }
+                    _ => {}
+                }
+
+                // Relationship changes and the user's own joins reach them on
+                // their topic, while other members coming and going is
+                // announced on the channel's.
+                let changes = if *topic == own_topic || topic.starts_with("C.") {
+                    subscriptions_spawn.lock().unwrap().apply(&event)
+                } else {
+                    Changes::default()
+                };
+
+                // Ephemeral events aren't worth replaying, so they skip the
+                // log and carry no `seq`. Those the old connection recorded
+                // before the RESUME went out with it already.
+                let frame = if priority == Priority::Low {
+                    Some(event.to_string())
+                } else if replayed.take(&event) {
+                    None
+                } else {
+                    match replay.record(&mut conn_spawn, event).await {
+                        Some(frame) => Some(frame),
+                        // The socket is already gone, so there's nobody left
+                        // to buffer for.
+                        None if outbound_spawn.is_none() => break,
+                        None => {
+                            resync(outbound_spawn.as_ref(), &mut resyncing);
+                            None
+                        }
+                    }
                 };
+
+                if let (Some(frame), Some(outbound)) = (frame, &outbound_spawn) {
+                    if !resyncing {
+                        outbound.send(frame, priority);
+                    }
+                }
+
+                if changes.is_empty() {
+                    continue;
+                }
+
+                let snapshot = subscriptions_spawn.lock().unwrap().clone();
+                let session_key = outbound_spawn.as_ref().map(|_| session_key_spawn.as_str());
+                update_subscriptions(
+                    &mut subscriber,
+                    &mut conn_spawn,
+                    &replay,
+                    &snapshot,
+                    &changes,
+                    session_key,
+                )
+                .await;
+
+                // Nobody told the client how newly followed users are doing yet.
+                for topic in &changes.added {
+                    let Some(uid) = topic.strip_prefix("F.") else {
+                        continue;
+                    };
+
+                    let update = ServerEvent::PresenceUpdate(PresenceUpdate {
+                        id: uid.to_owned(),
+                        presence: presence::get_for(&mut conn_spawn, uid, &user_id_spawn).await,
+                    });
+                    let event = serde_json::from_str(&update.frame()).unwrap();
+
+                    match (replay.record(&mut conn_spawn, event).await, &outbound_spawn) {
+                        (Some(frame), Some(outbound)) if !resyncing => {
+                            outbound.send(frame, Priority::Normal);
+                        }
+                        (None, outbound) => resync(outbound.as_ref(), &mut resyncing),
+                        _ => {}
+                    }
+                }
             }
         });
 
-        while let Some(Ok(msg)) = socket.lock().await.recv().await {
-            match decompress(msg) {
-                Ok(event) => match event.kind.as_str() {
-                    "PING" => {
-                        if socket
-                            .lock()
-                            .await
-                            .send(compress_msg(payload("PONG", None)))
-                            .await
-                            .is_err()
-                        {
-                            return;
-                        }
+        let mut limiter = RateLimiter::new(&user_id);
+        let mut resumable = true;
+
+        let mut ping_interval = tokio::time::interval(HEARTBEAT_INTERVAL);
+        ping_interval.reset();
+        let mut deadline = Instant::now() + HEARTBEAT_TIMEOUT;
+
+        loop {
+            let msg = tokio::select! {
+                msg = ws_stream.recv() => msg,
+
+                _ = &mut writer => break,
+
+                _ = ping_interval.tick() => {
+                    if !outbound.control(Message::Ping(Bytes::new())) {
+                        break;
                     }
+                    continue;
+                }
+
+                _ = shutdown::stopping() => {
+                    let delay = rand::random_range(0..shutdown::RECONNECT_JITTER.as_millis() as u64);
+                    outbound.send(ServerEvent::Reconnect { delay }.frame(), Priority::Normal);
+
+                    let close = Message::Close(Some(CloseFrame {
+                        code: shutdown::SERVICE_RESTART_CODE,
+                        reason: "Server restarting".into(),
+                    }));
+                    outbound.control(close);
+                    break;
+                }
 
-                    "PUSH_TOKEN" => {
-                        if let Ok(RealTimeEvents::PushToken { token }) =
-                            serde_json::from_value(event.data)
-                        {
-                            let pool = DB_POOL.get().unwrap();
-
-                            let push_token_res = sqlx::query(
-                                r#"INSERT INTO
-                            "push_tokens" ("id", "session_id", "token", "user_id")
-                                VALUES
-                                  ($1, $2, $3, $4)
-                                ON CONFLICT ("session_id") DO
-                                UPDATE
-                                SET
-                            "token" = $5"#,
-                            )
-                            .bind(cuid1().unwrap())
-                            .bind(&session.id)
-                            .bind(&token)
-                            .bind(&auth_user.me)
-                            .bind(&token)
-                            .execute(pool)
-                            .await;
-
-                            if push_token_res.is_err() {
-                                log_error(
-                                    "RealTimeRoutes_PUSH_TOKEN",
-                                    push_token_res.as_ref().err(),
-                                );
+                Some(control) = control.recv() => {
+                    let close = match control {
+                        Control::Kick => {
+                            resumable = false;
+                            CloseFrame {
+                                code: registry::KICKED_CODE,
+                                reason: "Disconnected by an administrator".into(),
                             }
-                        } else {
-                            // TODO: ERROR
                         }
+                        Control::Reconnect => {
+                            let delay = rand::random_range(0..shutdown::RECONNECT_JITTER.as_millis() as u64);
+                            outbound.send(ServerEvent::Reconnect { delay }.frame(), Priority::Normal);
+
+                            CloseFrame {
+                                code: shutdown::SERVICE_RESTART_CODE,
+                                reason: "Reconnect required".into(),
+                            }
+                        }
+                    };
+                    outbound.control(Message::Close(Some(close)));
+                    break;
+                }
+
+                _ = tokio::time::sleep_until(deadline) => {
+                    let close = Message::Close(Some(CloseFrame {
+                        code: HEARTBEAT_TIMEOUT_CODE,
+                        reason: "Heartbeat timeout".into(),
+                    }));
+                    outbound.control(close);
+                    break;
+                }
+            };
+
+            let Some(Ok(msg)) = msg else {
+                break;
+            };
+
+            deadline = Instant::now() + HEARTBEAT_TIMEOUT;
+
+            match msg {
+                Message::Ping(data) => {
+                    outbound.control(Message::Pong(data));
+                    continue;
+                }
+                Message::Pong(_) => continue,
+                Message::Close(frame) => {
+                    outbound.control(Message::Close(frame));
+                    break;
+                }
+                _ => {}
+            }
+
+            if let Err(retry_after) = limiter.check(&mut conn, ratelimit::ALL_EVENTS).await {
+                if !reject(&outbound, &limiter, retry_after) {
+                    break;
+                }
+                continue;
+            }
+
+            let event = match codec.decode(msg) {
+                Ok(frame) => events::parse(frame),
+                Err(err) => Err(ErrorData::new(ErrorCode::InvalidPayload, err)),
+            };
+
+            let event = match event {
+                Ok(event) => event,
+                Err(err) => {
+                    outbound.send(ServerEvent::Error(err).frame(), Priority::Normal);
+                    continue;
+                }
+            };
+
+            if let Err(retry_after) = limiter.check(&mut conn, event.kind()).await {
+                if !reject(&outbound, &limiter, retry_after) {
+                    break;
+                }
+                continue;
+            }
+
+            match event {
+                ClientEvent::Ping => {
+                    let pong = outbound.send(ServerEvent::Pong.frame(), Priority::Normal);
+
+                    if !pong {
+                        break;
                     }
+                }
 
-                    _ => {}
-                },
-                Err(_) => {}
+                ClientEvent::PushToken { token } => {
+                    let pool = DB_POOL.get().unwrap();
+
+                    let push_token_res = sqlx::query(
+                        r#"INSERT INTO
+                    "push_tokens" ("id", "session_id", "token", "user_id")
+                        VALUES
+                          ($1, $2, $3, $4)
+                        ON CONFLICT ("session_id") DO
+                        UPDATE
+                        SET
+                    "token" = $5"#,
+                    )
+                    .bind(cuid1().unwrap())
+                    .bind(&session.id)
+                    .bind(&token)
+                    .bind(&auth_user.me)
+                    .bind(&token)
+                    .execute(pool)
+                    .await;
+
+                    if push_token_res.is_err() {
+                        log_error("RealTimeRoutes_PUSH_TOKEN", push_token_res.as_ref().err());
+                    }
+                }
+
+                ClientEvent::PresenceSet {
+                    status,
+                    custom_status,
+                } => {
+                    let text_len = custom_status
+                        .as_ref()
+                        .and_then(|c| c.text.as_ref())
+                        .map_or(0, |t| t.chars().count());
+
+                    if text_len > presence::MAX_STATUS_TEXT {
+                        let err = ErrorData::new(
+                            ErrorCode::InvalidPayload,
+                            format!(
+                                "Custom status can't be longer than {} characters",
+                                presence::MAX_STATUS_TEXT
+                            ),
+                        );
+                        outbound.send(ServerEvent::Error(err).frame(), Priority::Normal);
+                        continue;
+                    }
+
+                    presence::set_status(&mut conn, &user_id, status, custom_status.as_ref()).await;
+
+                    let friend_ids = subscriptions.lock().unwrap().users();
+                    presence::broadcast(&mut conn, &user_id, &friend_ids).await;
+                }
             }
         }
 
         println!("Session ID {} Disconnected", &session.id);
 
-        let mut pubsub = client.get_async_pubsub().await.unwrap();
-        let mut conn_close = client.get_multiplexed_async_connection().await.unwrap();
-        let sub_key = format!("user:session:{}:subscriptions", &session.id);
-        let subscriptions = conn_close
-            .smembers::<_, Vec<String>>(&sub_key)
-            .await
-            .unwrap_or(vec![]);
+        // The forwarder drops its hub subscriber, which is what actually
+        // unsubscribes this session, once the resume window is over.
+        detach.send(resumable).ok();
 
-        println!("{:?}", subscriptions);
-        pubsub.unsubscribe(subscriptions).await.ok();
-        conn_close.del::<_, String>(&sub_key).await.unwrap();
+        // Let the writer flush whatever is queued, like a close frame, but
+        // don't wait on a client that stopped reading.
+        drop(outbound);
+        if !writer.is_finished()
+            && tokio::time::timeout(WRITER_DRAIN_TIMEOUT, &mut writer)
+                .await
+                .is_err()
+        {
+            writer.abort();
+        }
+
+        conn.del::<_, ()>(&session_key).await.ok();
+
+        // A connection reaped while this node was presumed dead already
+        // released its presence.
+        if registry::unregister(&mut conn, &connection_id, &user_id).await
+            && presence::disconnect(&mut conn, &user_id, 1).await
+        {
+            let friend_ids = subscriptions.lock().unwrap().users();
+            presence::broadcast(&mut conn, &user_id, &friend_ids).await;
+        }
     }
 }
@@ -0,0 +1,100 @@
+use std::{
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use once_cell::sync::Lazy;
+use tokio::sync::{watch, Notify};
+
+/// Longest a client is told to wait before reconnecting. Each gets a random
+/// delay below it so they don't all land on the remaining nodes at once.
+pub const RECONNECT_JITTER: Duration = Duration::from_secs(10);
+
+/// Close code for sockets closed by a deploy, "Service Restart" in RFC 6455.
+pub const SERVICE_RESTART_CODE: u16 = 1012;
+
+/// How long sockets get to run their disconnect cleanup before the process
+/// exits regardless.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+static STOPPING: Lazy<watch::Sender<bool>> = Lazy::new(|| watch::channel(false).0);
+
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+
+static DRAINED: Notify = Notify::const_new();
+
+/// Resolves on SIGINT or SIGTERM, after telling every socket to wind down.
+pub async fn signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c().await.ok();
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        let mut terminate =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()).unwrap();
+        terminate.recv().await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+
+    println!("Shutting down, draining connections");
+    STOPPING.send_replace(true);
+}
+
+pub fn is_stopping() -> bool {
+    *STOPPING.borrow()
+}
+
+/// Resolves once shutdown has begun, right away if it already has.
+pub async fn stopping() {
+    STOPPING
+        .subscribe()
+        .wait_for(|stopping| *stopping)
+        .await
+        .ok();
+}
+
+/// Held by socket tasks until their cleanup is done, so shutdown waits on them.
+pub struct Guard(());
+
+pub fn guard() -> Guard {
+    ACTIVE.fetch_add(1, Ordering::SeqCst);
+    Guard(())
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        if ACTIVE.fetch_sub(1, Ordering::SeqCst) == 1 {
+            DRAINED.notify_waiters();
+        }
+    }
+}
+
+/// Waits for every [`Guard`] to be dropped, up to [`DRAIN_TIMEOUT`].
+pub async fn drain() {
+    let drained = async {
+        loop {
+            // Registered before checking, so a drop in between isn't missed.
+            let notified = DRAINED.notified();
+            if ACTIVE.load(Ordering::SeqCst) == 0 {
+                return;
+            }
+            notified.await;
+        }
+    };
+
+    if tokio::time::timeout(DRAIN_TIMEOUT, drained).await.is_err() {
+        println!(
+            "{} connections still open after {:?}, exiting anyway",
+            ACTIVE.load(Ordering::SeqCst),
+            DRAIN_TIMEOUT
+        );
+    }
+}
@@ -0,0 +1,231 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// What a connection follows over pub/sub, and why, so a user's topic is
+/// only dropped once neither a friendship nor a shared channel needs it.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Subscriptions {
+    uid: String,
+    friends: HashSet<String>,
+    /// Members of every channel the user is in.
+    channels: HashMap<String, HashSet<String>>,
+}
+
+/// How the topics changed after an event.
+#[derive(Default)]
+pub struct Changes {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+impl Changes {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty()
+    }
+}
+
+fn members(channel: &Value) -> HashSet<String> {
+    channel["members"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|member| member.as_str().map(str::to_owned))
+        .collect()
+}
+
+/// The other side of a relationship, once the request is accepted.
+fn friend(relationship: &Value) -> Option<String> {
+    if relationship["accepted_at"].is_null() {
+        return None;
+    }
+    relationship["user_id"].as_str().map(str::to_owned)
+}
+
+impl Subscriptions {
+    /// Builds the set from the `relationships` and `channels` sent in `INIT`.
+    pub fn new(uid: &str, relationships: &Value, channels: &Value) -> Self {
+        let friends = relationships
+            .as_object()
+            .into_iter()
+            .flatten()
+            .filter_map(|(_, relationship)| friend(relationship))
+            .collect();
+
+        let channels = channels
+            .as_object()
+            .into_iter()
+            .flatten()
+            .map(|(id, channel)| (id.clone(), members(channel)))
+            .collect();
+
+        Subscriptions {
+            uid: uid.to_owned(),
+            friends,
+            channels,
+        }
+    }
+
+    /// Friends and channel members, everyone whose events the user follows.
+    pub fn users(&self) -> Vec<String> {
+        self.friends
+            .iter()
+            .chain(self.channels.values().flatten())
+            .filter(|id| **id != self.uid)
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .cloned()
+            .collect()
+    }
+
+    /// The user's own topic, one per followed user, and one per channel so a
+    /// message is published once no matter how many members it has.
+    pub fn topics(&self) -> Vec<String> {
+        let mut topics = vec![format!("U.{}", self.uid)];
+        topics.extend(self.users().iter().map(|id| format!("F.{}", id)));
+
+        let mut channels: Vec<&String> = self.channels.keys().collect();
+        channels.sort();
+        topics.extend(channels.into_iter().map(|id| format!("C.{}", id)));
+
+        topics
+    }
+
+    /// Updates the set for an event delivered to the user or one of their
+    /// channels and returns how the topics changed. Anything but relationship
+    /// and membership events leaves it as is.
+    pub fn apply(&mut self, event: &Value) -> Changes {
+        let data = &event["data"];
+        let field = |key: &str| data[key].as_str().map(str::to_owned);
+        let before = self.topics();
+
+        match event["type"].as_str().unwrap_or_default() {
+            "RELATIONSHIP_ADD" | "RELATIONSHIP_UPDATE" => {
+                if let Some(user_id) = friend(data) {
+                    self.friends.insert(user_id);
+                }
+            }
+            "RELATIONSHIP_REMOVE" => {
+                if let Some(user_id) = field("user_id") {
+                    self.friends.remove(&user_id);
+                }
+            }
+            "CHANNEL_CREATE" => {
+                if let Some(id) = field("id") {
+                    self.channels.insert(id, members(data));
+                }
+            }
+            "CHANNEL_DELETE" => {
+                if let Some(id) = field("id") {
+                    self.channels.remove(&id);
+                }
+            }
+            "CHANNEL_MEMBER_ADD" => {
+                if let (Some(channel_id), Some(user_id)) = (field("channel_id"), field("user_id")) {
+                    if let Some(members) = self.channels.get_mut(&channel_id) {
+                        members.insert(user_id);
+                    }
+                }
+            }
+            "CHANNEL_MEMBER_REMOVE" => {
+                if let (Some(channel_id), Some(user_id)) = (field("channel_id"), field("user_id")) {
+                    if user_id == self.uid {
+                        self.channels.remove(&channel_id);
+                    } else if let Some(members) = self.channels.get_mut(&channel_id) {
+                        members.remove(&user_id);
+                    }
+                }
+            }
+            _ => return Changes::default(),
+        }
+
+        let after = self.topics();
+        Changes {
+            added: after
+                .iter()
+                .filter(|t| !before.contains(t))
+                .cloned()
+                .collect(),
+            removed: before
+                .iter()
+                .filter(|t| !after.contains(t))
+                .cloned()
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn relationship(user_id: &str, accepted: bool) -> Value {
+        json!({
+            "id": format!("r-{}", user_id),
+            "user_id": user_id,
+            "incoming": false,
+            "accepted_at": accepted.then_some("2026-10-18T00:00:00Z"),
+            "created_at": "2026-10-18T00:00:00Z",
+        })
+    }
+
+    fn event(kind: &str, data: Value) -> Value {
+        json!({ "type": kind, "data": data })
+    }
+
+    #[test]
+    fn new_follows_accepted_friends_only() {
+        let relationships = json!({
+            "r-b": relationship("b", true),
+            "r-c": relationship("c", false),
+        });
+        let subscriptions = Subscriptions::new("a", &relationships, &json!({}));
+
+        assert_eq!(subscriptions.users(), ["b"]);
+    }
+
+    #[test]
+    fn pending_request_is_not_followed() {
+        let mut subscriptions = Subscriptions::new("a", &json!({}), &json!({}));
+        let changes = subscriptions.apply(&event("RELATIONSHIP_ADD", relationship("b", false)));
+
+        assert!(changes.is_empty());
+        assert!(subscriptions.users().is_empty());
+    }
+
+    #[test]
+    fn accepted_request_is_followed() {
+        let mut subscriptions = Subscriptions::new("a", &json!({}), &json!({}));
+        subscriptions.apply(&event("RELATIONSHIP_ADD", relationship("b", false)));
+        let changes = subscriptions.apply(&event("RELATIONSHIP_UPDATE", relationship("b", true)));
+
+        assert_eq!(changes.added, ["F.b"]);
+        assert!(changes.removed.is_empty());
+        assert_eq!(subscriptions.users(), ["b"]);
+    }
+
+    #[test]
+    fn removed_friend_is_dropped() {
+        let relationships = json!({ "r-b": relationship("b", true) });
+        let mut subscriptions = Subscriptions::new("a", &relationships, &json!({}));
+        let changes = subscriptions.apply(&event("RELATIONSHIP_REMOVE", relationship("b", true)));
+
+        assert!(changes.added.is_empty());
+        assert_eq!(changes.removed, ["F.b"]);
+        assert!(subscriptions.users().is_empty());
+    }
+
+    #[test]
+    fn removed_friend_in_a_shared_channel_is_kept() {
+        let relationships = json!({ "r-b": relationship("b", true) });
+        let channels = json!({ "ch": { "members": ["a", "b"] } });
+        let mut subscriptions = Subscriptions::new("a", &relationships, &channels);
+        let changes = subscriptions.apply(&event("RELATIONSHIP_REMOVE", relationship("b", true)));
+
+        assert!(changes.is_empty());
+        assert_eq!(subscriptions.users(), ["b"]);
+    }
+}
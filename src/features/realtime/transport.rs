@@ -0,0 +1,1128 @@
+//! WebSocket server transport with RFC 7692 `permessage-deflate`.
+//!
+//! axum's WebSocket refuses frames with RSV1 set, which is how compressed
+//! messages are flagged, so the upgrade and framing are done here instead.
+//! Messages are still axum's [`Message`], so the rest of the module doesn't
+//! care which transport it runs on.
+
+use std::{future::Future, io};
+
+use axum::{
+    extract::{
+        ws::{CloseFrame, Message},
+        FromRequestParts,
+    },
+    http::{header, request::Parts, HeaderMap, HeaderValue, Method, StatusCode},
+    response::{IntoResponse, Response},
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bytes::{Buf, BytesMut};
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+use hyper::upgrade::{OnUpgrade, Upgraded};
+use hyper_util::rt::TokioIo;
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
+
+/// Largest message we'll accept, after inflating.
+const MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+
+/// Appended to the key to derive `Sec-WebSocket-Accept`.
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Every sync-flushed deflate block ends with these bytes, which the
+/// extension leaves off the wire.
+const DEFLATE_TAIL: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xa;
+
+type Io = TokioIo<Upgraded>;
+
+fn protocol_error(reason: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+/// `permessage-deflate` parameters agreed on during the handshake.
+#[derive(Clone, Copy)]
+struct DeflateParams {
+    server_no_context_takeover: bool,
+    client_no_context_takeover: bool,
+    server_max_window_bits: u8,
+}
+
+impl DeflateParams {
+    /// Picks the first offer in the request we can honour.
+    fn negotiate(headers: &HeaderMap) -> Option<Self> {
+        headers
+            .get_all(header::SEC_WEBSOCKET_EXTENSIONS)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .find_map(Self::accept)
+    }
+
+    fn accept(offer: &str) -> Option<Self> {
+        let mut params = offer.split(';').map(str::trim);
+        if params.next()? != "permessage-deflate" {
+            return None;
+        }
+
+        let mut accepted = DeflateParams {
+            server_no_context_takeover: false,
+            client_no_context_takeover: false,
+            server_max_window_bits: 15,
+        };
+        let mut seen = Vec::new();
+
+        for param in params {
+            let (name, value) = match param.split_once('=') {
+                Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+                None => (param, None),
+            };
+
+            if seen.contains(&name) {
+                return None;
+            }
+            seen.push(name);
+
+            let window_bits = value.and_then(|bits| bits.parse::<u8>().ok());
+            match (name, value) {
+                ("server_no_context_takeover", None) => {
+                    accepted.server_no_context_takeover = true;
+                }
+                ("client_no_context_takeover", None) => {
+                    accepted.client_no_context_takeover = true;
+                }
+                // zlib can't write raw deflate with an 8 bit window, so such
+                // an offer is declined rather than answered with a larger one.
+                ("server_max_window_bits", Some(_)) => {
+                    accepted.server_max_window_bits =
+                        window_bits.filter(|bits| (9..=15).contains(bits))?;
+                }
+                // We always inflate with the largest window, which reads
+                // whatever the client settles on.
+                ("client_max_window_bits", None) => {}
+                ("client_max_window_bits", Some(_)) => {
+                    window_bits.filter(|bits| (8..=15).contains(bits))?;
+                }
+                _ => return None,
+            }
+        }
+
+        Some(accepted)
+    }
+
+    fn response(&self) -> String {
+        let mut response = "permessage-deflate".to_owned();
+
+        if self.server_no_context_takeover {
+            response.push_str("; server_no_context_takeover");
+        }
+        if self.client_no_context_takeover {
+            response.push_str("; client_no_context_takeover");
+        }
+        if self.server_max_window_bits < 15 {
+            response.push_str(&format!(
+                "; server_max_window_bits={}",
+                self.server_max_window_bits
+            ));
+        }
+
+        response
+    }
+}
+
+/// `Sec-WebSocket-Accept` for a client's `Sec-WebSocket-Key`.
+fn accept_key(key: &[u8]) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.update(key);
+    sha1.update(ACCEPT_GUID.as_bytes());
+    STANDARD.encode(sha1.finalize())
+}
+
+fn has_token(headers: &HeaderMap, name: header::HeaderName, token: &str) -> bool {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|value| value.trim().eq_ignore_ascii_case(token))
+}
+
+/// Extractor for a WebSocket handshake, counterpart of axum's `WebSocketUpgrade`.
+pub struct WebSocketUpgrade {
+    on_upgrade: OnUpgrade,
+    accept: String,
+    deflate: Option<DeflateParams>,
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for WebSocketUpgrade {
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let headers = &parts.headers;
+
+        if parts.method != Method::GET
+            || !has_token(headers, header::CONNECTION, "upgrade")
+            || !has_token(headers, header::UPGRADE, "websocket")
+        {
+            return Err((StatusCode::BAD_REQUEST, "Expected a WebSocket upgrade"));
+        }
+
+        if headers
+            .get(header::SEC_WEBSOCKET_VERSION)
+            .map(HeaderValue::as_bytes)
+            != Some(b"13")
+        {
+            return Err((StatusCode::BAD_REQUEST, "Unsupported WebSocket version"));
+        }
+
+        let Some(key) = headers.get(header::SEC_WEBSOCKET_KEY) else {
+            return Err((StatusCode::BAD_REQUEST, "Missing Sec-WebSocket-Key"));
+        };
+
+        let accept = accept_key(key.as_bytes());
+
+        let deflate = DeflateParams::negotiate(headers);
+
+        let Some(on_upgrade) = parts.extensions.remove::<OnUpgrade>() else {
+            return Err((StatusCode::UPGRADE_REQUIRED, "Connection can't be upgraded"));
+        };
+
+        Ok(WebSocketUpgrade {
+            on_upgrade,
+            accept,
+            deflate,
+        })
+    }
+}
+
+impl WebSocketUpgrade {
+    /// Finishes the handshake and hands the socket to `callback` once the
+    /// connection has switched protocols.
+    pub fn on_upgrade<F, Fut>(self, callback: F) -> Response
+    where
+        F: FnOnce(WebSocket) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let deflate = self.deflate;
+        let on_upgrade = self.on_upgrade;
+        tokio::spawn(async move {
+            let Ok(upgraded) = on_upgrade.await else {
+                return;
+            };
+
+            callback(WebSocket {
+                io: TokioIo::new(upgraded),
+                deflate,
+            })
+            .await;
+        });
+
+        let mut response = (
+            StatusCode::SWITCHING_PROTOCOLS,
+            [
+                (header::CONNECTION, "upgrade".to_owned()),
+                (header::UPGRADE, "websocket".to_owned()),
+                (header::SEC_WEBSOCKET_ACCEPT, self.accept),
+            ],
+        )
+            .into_response();
+
+        if let Some(deflate) = deflate {
+            response.headers_mut().insert(
+                header::SEC_WEBSOCKET_EXTENSIONS,
+                HeaderValue::from_str(&deflate.response()).unwrap(),
+            );
+        }
+
+        response
+    }
+}
+
+pub struct WebSocket {
+    io: Io,
+    deflate: Option<DeflateParams>,
+}
+
+impl WebSocket {
+    pub fn split(self) -> (Sender, Receiver) {
+        let (read, write) = tokio::io::split(self.io);
+        (
+            Sender::new(write, self.deflate),
+            Receiver::new(read, self.deflate),
+        )
+    }
+}
+
+struct Deflater {
+    compress: Compress,
+    /// Set when the client asked for `server_no_context_takeover`.
+    reset: bool,
+}
+
+impl Deflater {
+    fn new(params: DeflateParams) -> Self {
+        Deflater {
+            compress: Compress::new_with_window_bits(
+                Compression::default(),
+                false,
+                params.server_max_window_bits,
+            ),
+            reset: params.server_no_context_takeover,
+        }
+    }
+
+    fn deflate(&mut self, input: &[u8]) -> io::Result<Vec<u8>> {
+        let before = self.compress.total_in();
+        let mut out = Vec::with_capacity(input.len() / 2 + 64);
+
+        loop {
+            if out.len() == out.capacity() {
+                out.reserve(out.capacity());
+            }
+
+            let consumed = (self.compress.total_in() - before) as usize;
+            self.compress
+                .compress_vec(&input[consumed..], &mut out, FlushCompress::Sync)
+                .map_err(io::Error::other)?;
+
+            // A sync flush is complete once all input went in and it didn't
+            // run out of room for output.
+            let consumed = (self.compress.total_in() - before) as usize;
+            if consumed == input.len() && out.len() < out.capacity() {
+                break;
+            }
+        }
+
+        if out.ends_with(&DEFLATE_TAIL) {
+            out.truncate(out.len() - DEFLATE_TAIL.len());
+        }
+
+        if self.reset {
+            self.compress.reset();
+        }
+
+        Ok(out)
+    }
+}
+
+struct Inflater {
+    decompress: Decompress,
+    /// Set when the client agreed to `client_no_context_takeover`.
+    reset: bool,
+}
+
+impl Inflater {
+    fn new(params: DeflateParams) -> Self {
+        Inflater {
+            decompress: Decompress::new_with_window_bits(false, 15),
+            reset: params.client_no_context_takeover,
+        }
+    }
+
+    fn inflate(&mut self, mut input: Vec<u8>) -> io::Result<Vec<u8>> {
+        input.extend_from_slice(&DEFLATE_TAIL);
+
+        let before = self.decompress.total_in();
+        let mut out = Vec::with_capacity((input.len() * 4).min(MAX_MESSAGE_SIZE));
+
+        loop {
+            if out.len() == out.capacity() {
+                if out.len() >= MAX_MESSAGE_SIZE {
+                    return Err(protocol_error("Message too big"));
+                }
+                out.reserve(out.capacity().max(64));
+            }
+
+            let (total_in, written) = (self.decompress.total_in(), out.len());
+            let consumed = (total_in - before) as usize;
+            let status = self
+                .decompress
+                .decompress_vec(&input[consumed..], &mut out, FlushDecompress::Sync)
+                .map_err(|_| protocol_error("Invalid deflate data"))?;
+
+            let consumed = (self.decompress.total_in() - before) as usize;
+            if status == Status::StreamEnd
+                || (consumed == input.len() && out.len() < out.capacity())
+            {
+                break;
+            }
+
+            if self.decompress.total_in() == total_in && out.len() == written {
+                return Err(protocol_error("Invalid deflate data"));
+            }
+        }
+
+        if out.len() > MAX_MESSAGE_SIZE {
+            return Err(protocol_error("Message too big"));
+        }
+
+        if self.reset {
+            self.decompress.reset(false);
+        }
+
+        Ok(out)
+    }
+}
+
+/// Writing half of a [`WebSocket`].
+pub struct Sender<W = WriteHalf<Io>> {
+    io: W,
+    deflate: Option<Deflater>,
+    compress_messages: bool,
+}
+
+impl<W: AsyncWrite + Unpin> Sender<W> {
+    fn new(io: W, deflate: Option<DeflateParams>) -> Self {
+        Sender {
+            io,
+            deflate: deflate.map(Deflater::new),
+            compress_messages: true,
+        }
+    }
+
+    /// Whether data messages go out compressed when `permessage-deflate` was
+    /// negotiated. Worth turning off for payloads that are compressed already.
+    pub fn set_compression(&mut self, enabled: bool) {
+        self.compress_messages = enabled;
+    }
+
+    pub async fn send(&mut self, msg: Message) -> io::Result<()> {
+        let (opcode, payload) = match msg {
+            Message::Text(text) => (OP_TEXT, text.as_str().as_bytes().to_vec()),
+            Message::Binary(bytes) => (OP_BINARY, bytes.to_vec()),
+            Message::Ping(bytes) => (OP_PING, bytes.to_vec()),
+            Message::Pong(bytes) => (OP_PONG, bytes.to_vec()),
+            Message::Close(frame) => {
+                let mut payload = Vec::new();
+                if let Some(CloseFrame { code, reason }) = frame {
+                    payload.extend_from_slice(&code.to_be_bytes());
+                    payload.extend_from_slice(reason.as_str().as_bytes());
+                }
+                (OP_CLOSE, payload)
+            }
+        };
+
+        let deflater = self
+            .deflate
+            .as_mut()
+            .filter(|_| self.compress_messages && (opcode == OP_TEXT || opcode == OP_BINARY));
+
+        let (rsv1, payload) = match deflater {
+            Some(deflater) => (0x40, deflater.deflate(&payload)?),
+            None => (0, payload),
+        };
+
+        let mut frame = Vec::with_capacity(payload.len() + 10);
+        frame.push(0x80 | rsv1 | opcode);
+        match payload.len() {
+            len if len < 126 => frame.push(len as u8),
+            len if len <= u16::MAX as usize => {
+                frame.push(126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(&payload);
+
+        self.io.write_all(&frame).await?;
+        self.io.flush().await
+    }
+}
+
+struct Frame {
+    fin: bool,
+    rsv1: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+/// Reading half of a [`WebSocket`].
+pub struct Receiver<R = ReadHalf<Io>> {
+    io: R,
+    buf: BytesMut,
+    inflater: Option<Inflater>,
+    /// Opcode and compression flag of a fragmented message in progress.
+    message: Option<(u8, bool)>,
+    payload: Vec<u8>,
+}
+
+impl<R: AsyncRead + Unpin> Receiver<R> {
+    fn new(io: R, deflate: Option<DeflateParams>) -> Self {
+        Receiver {
+            io,
+            buf: BytesMut::with_capacity(8 * 1024),
+            inflater: deflate.map(Inflater::new),
+            message: None,
+            payload: Vec::new(),
+        }
+    }
+
+    /// Waits for the next message. Pings are returned like anything else and
+    /// are up to the caller to answer.
+    ///
+    /// Cancel safe: partial frames stay buffered until the rest arrives.
+    pub async fn recv(&mut self) -> Option<io::Result<Message>> {
+        loop {
+            match self.parse() {
+                Ok(Some(msg)) => return Some(Ok(msg)),
+                Ok(None) => {}
+                Err(err) => return Some(Err(err)),
+            }
+
+            match self.io.read_buf(&mut self.buf).await {
+                Ok(0) => return None,
+                Ok(_) => {}
+                Err(err) => return Some(Err(err)),
+            }
+        }
+    }
+
+    /// Consumes buffered frames until one completes a message.
+    fn parse(&mut self) -> io::Result<Option<Message>> {
+        while let Some(frame) = self.next_frame()? {
+            if let Some(msg) = self.assemble(frame)? {
+                return Ok(Some(msg));
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn next_frame(&mut self) -> io::Result<Option<Frame>> {
+        let buf = &self.buf;
+        if buf.len() < 2 {
+            return Ok(None);
+        }
+
+        let (head, len) = (buf[0], buf[1]);
+        if len & 0x80 == 0 {
+            return Err(protocol_error("Client frames must be masked"));
+        }
+        if head & 0x30 != 0 {
+            return Err(protocol_error("Reserved bits set"));
+        }
+
+        let (len, offset) = match len & 0x7f {
+            126 if buf.len() < 4 => return Ok(None),
+            126 => (u16::from_be_bytes([buf[2], buf[3]]) as u64, 4),
+            127 if buf.len() < 10 => return Ok(None),
+            127 => (u64::from_be_bytes(buf[2..10].try_into().unwrap()), 10),
+            len => (len as u64, 2),
+        };
+
+        if len > MAX_MESSAGE_SIZE as u64 {
+            return Err(protocol_error("Message too big"));
+        }
+
+        let len = len as usize;
+        let frame_len = offset + 4 + len;
+        if buf.len() < frame_len {
+            let missing = frame_len - buf.len();
+            self.buf.reserve(missing);
+            return Ok(None);
+        }
+
+        let mask: [u8; 4] = buf[offset..offset + 4].try_into().unwrap();
+        self.buf.advance(offset + 4);
+
+        let mut payload = self.buf.split_to(len).to_vec();
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+
+        Ok(Some(Frame {
+            fin: head & 0x80 != 0,
+            rsv1: head & 0x40 != 0,
+            opcode: head & 0x0f,
+            payload,
+        }))
+    }
+
+    fn assemble(&mut self, frame: Frame) -> io::Result<Option<Message>> {
+        match frame.opcode {
+            OP_CLOSE | OP_PING | OP_PONG => {
+                if !frame.fin || frame.rsv1 || frame.payload.len() > 125 {
+                    return Err(protocol_error("Invalid control frame"));
+                }
+
+                return Ok(Some(match frame.opcode {
+                    OP_PING => Message::Ping(frame.payload.into()),
+                    OP_PONG => Message::Pong(frame.payload.into()),
+                    _ => Message::Close(close_frame(frame.payload)?),
+                }));
+            }
+
+            OP_TEXT | OP_BINARY => {
+                if self.message.is_some() {
+                    return Err(protocol_error("Expected a continuation frame"));
+                }
+                if frame.rsv1 && self.inflater.is_none() {
+                    return Err(protocol_error("Compression wasn't negotiated"));
+                }
+
+                self.message = Some((frame.opcode, frame.rsv1));
+                self.payload.clear();
+            }
+
+            OP_CONTINUATION => {
+                if self.message.is_none() || frame.rsv1 {
+                    return Err(protocol_error("Unexpected continuation frame"));
+                }
+            }
+
+            _ => return Err(protocol_error("Unknown opcode")),
+        }
+
+        if self.payload.len() + frame.payload.len() > MAX_MESSAGE_SIZE {
+            return Err(protocol_error("Message too big"));
+        }
+        self.payload.extend_from_slice(&frame.payload);
+
+        if !frame.fin {
+            return Ok(None);
+        }
+
+        let (opcode, compressed) = self.message.take().unwrap();
+        let mut payload = std::mem::take(&mut self.payload);
+
+        if compressed {
+            payload = self.inflater.as_mut().unwrap().inflate(payload)?;
+        }
+
+        Ok(Some(if opcode == OP_TEXT {
+            let text = String::from_utf8(payload).map_err(|_| protocol_error("Invalid UTF-8"))?;
+            Message::Text(text.into())
+        } else {
+            Message::Binary(payload.into())
+        }))
+    }
+}
+
+fn close_frame(payload: Vec<u8>) -> io::Result<Option<CloseFrame>> {
+    match payload.len() {
+        0 => Ok(None),
+        1 => Err(protocol_error("Invalid close frame")),
+        _ => {
+            let code = u16::from_be_bytes([payload[0], payload[1]]);
+            let reason =
+                std::str::from_utf8(&payload[2..]).map_err(|_| protocol_error("Invalid UTF-8"))?;
+
+            Ok(Some(CloseFrame {
+                code,
+                reason: reason.into(),
+            }))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use bytes::Bytes;
+
+    use super::*;
+
+    const MASK: [u8; 4] = [0x37, 0xfa, 0x21, 0x3d];
+
+    /// RFC 7692 section 7.2.3.1, "Hello" compressed on its own.
+    const HELLO_DEFLATED: [u8; 7] = [0xf2, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00];
+
+    /// RFC 7692 section 7.2.3.2, a second "Hello" referring back to the first.
+    const HELLO_AGAIN_DEFLATED: [u8; 5] = [0xf2, 0x00, 0x11, 0x00, 0x00];
+
+    fn deflate(
+        server_no_context_takeover: bool,
+        client_no_context_takeover: bool,
+    ) -> DeflateParams {
+        DeflateParams {
+            server_no_context_takeover,
+            client_no_context_takeover,
+            server_max_window_bits: 15,
+        }
+    }
+
+    /// An unmasked frame, as a server sends it.
+    fn frame(head: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![head];
+        match payload.len() {
+            len if len < 126 => frame.push(len as u8),
+            len if len <= u16::MAX as usize => {
+                frame.push(126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    /// `frame` masked with [`MASK`], as a client sends it.
+    fn masked(frame: &[u8]) -> Vec<u8> {
+        let offset = match frame[1] & 0x7f {
+            126 => 4,
+            127 => 10,
+            _ => 2,
+        };
+
+        let mut out = frame[..offset].to_vec();
+        out[1] |= 0x80;
+        out.extend_from_slice(&MASK);
+        out.extend(
+            frame[offset..]
+                .iter()
+                .enumerate()
+                .map(|(i, byte)| byte ^ MASK[i % 4]),
+        );
+        out
+    }
+
+    /// Everything `frames` read as, up to the end of the input or the first error.
+    async fn read(frames: &[Vec<u8>], deflate: Option<DeflateParams>) -> Vec<io::Result<Message>> {
+        let mut receiver = Receiver::new(Cursor::new(frames.concat()), deflate);
+        let mut messages = Vec::new();
+
+        while let Some(msg) = receiver.recv().await {
+            let failed = msg.is_err();
+            messages.push(msg);
+            if failed {
+                break;
+            }
+        }
+
+        messages
+    }
+
+    async fn read_one(frames: &[Vec<u8>], deflate: Option<DeflateParams>) -> io::Result<Message> {
+        let mut messages = read(frames, deflate).await;
+        assert_eq!(messages.len(), 1);
+        messages.pop().unwrap()
+    }
+
+    fn text(text: &str) -> Message {
+        Message::Text(text.into())
+    }
+
+    async fn sent(deflate: Option<DeflateParams>, messages: Vec<Message>) -> Vec<u8> {
+        let mut sender = Sender::new(Vec::new(), deflate);
+        for msg in messages {
+            sender.send(msg).await.unwrap();
+        }
+        sender.io
+    }
+
+    #[test]
+    fn accept_key_matches_rfc_6455() {
+        assert_eq!(
+            accept_key(b"dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn negotiates_first_acceptable_offer() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::SEC_WEBSOCKET_EXTENSIONS,
+            HeaderValue::from_static(
+                "permessage-deflate; server_max_window_bits=8, \
+                 permessage-deflate; client_max_window_bits; server_no_context_takeover",
+            ),
+        );
+
+        let params = DeflateParams::negotiate(&headers).unwrap();
+        assert!(params.server_no_context_takeover);
+        assert!(!params.client_no_context_takeover);
+        assert_eq!(
+            params.response(),
+            "permessage-deflate; server_no_context_takeover"
+        );
+    }
+
+    #[test]
+    fn declines_malformed_offers() {
+        for offer in [
+            "x-webkit-deflate-frame",
+            "permessage-deflate; server_max_window_bits=16",
+            "permessage-deflate; server_max_window_bits",
+            "permessage-deflate; client_max_window_bits=7",
+            "permessage-deflate; server_no_context_takeover=1",
+            "permessage-deflate; client_no_context_takeover; client_no_context_takeover",
+            "permessage-deflate; unknown",
+        ] {
+            assert!(DeflateParams::accept(offer).is_none(), "{offer}");
+        }
+
+        let params = DeflateParams::accept(
+            r#"permessage-deflate; client_no_context_takeover; server_max_window_bits="10""#,
+        )
+        .unwrap();
+        assert_eq!(
+            params.response(),
+            "permessage-deflate; client_no_context_takeover; server_max_window_bits=10"
+        );
+    }
+
+    #[tokio::test]
+    async fn reads_masked_text_frame() {
+        // RFC 6455 section 5.7, a single-frame masked text message.
+        let frame = vec![
+            0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+        ];
+        assert_eq!(read_one(&[frame], None).await.unwrap(), text("Hello"));
+    }
+
+    #[tokio::test]
+    async fn rejects_unmasked_frames() {
+        let err = read_one(&[frame(0x81, b"Hello")], None).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "Client frames must be masked");
+    }
+
+    #[tokio::test]
+    async fn reads_fragmented_message() {
+        let frames = [masked(&frame(0x01, b"Hel")), masked(&frame(0x80, b"lo"))];
+        assert_eq!(read_one(&frames, None).await.unwrap(), text("Hello"));
+    }
+
+    #[tokio::test]
+    async fn reads_control_frames_between_fragments() {
+        let frames = [
+            masked(&frame(0x01, b"Hel")),
+            masked(&frame(0x89, b"Hello")),
+            masked(&frame(0x00, b"l")),
+            masked(&frame(0x8a, b"")),
+            masked(&frame(0x80, b"o")),
+        ];
+
+        let messages: Vec<Message> = read(&frames, None)
+            .await
+            .into_iter()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(
+            messages,
+            [
+                Message::Ping(Bytes::from_static(b"Hello")),
+                Message::Pong(Bytes::new()),
+                text("Hello"),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn reads_extended_lengths() {
+        let medium = vec![0x2a; 256];
+        let large = vec![0x2a; 65536];
+
+        let frames = [masked(&frame(0x82, &medium)), masked(&frame(0x82, &large))];
+        assert_eq!(frames[0][1..4], [0xfe, 0x01, 0x00]);
+        assert_eq!(frames[1][1..10], [0xff, 0, 0, 0, 0, 0, 1, 0, 0]);
+
+        let messages: Vec<Message> = read(&frames, None)
+            .await
+            .into_iter()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(
+            messages,
+            [
+                Message::Binary(medium.into()),
+                Message::Binary(large.into())
+            ]
+        );
+    }
+
+    #[test]
+    fn waits_for_partial_frames() {
+        let bytes = masked(&frame(0x81, b"Hello"));
+        let mut receiver = Receiver::new(Cursor::new(Vec::new()), None);
+
+        for byte in &bytes[..bytes.len() - 1] {
+            receiver.buf.extend_from_slice(&[*byte]);
+            assert!(receiver.parse().unwrap().is_none());
+        }
+
+        receiver.buf.extend_from_slice(&bytes[bytes.len() - 1..]);
+        assert_eq!(receiver.parse().unwrap(), Some(text("Hello")));
+    }
+
+    #[tokio::test]
+    async fn reads_close_frames() {
+        let frames = [masked(&frame(0x88, b"\x03\xe8bye"))];
+        assert_eq!(
+            read_one(&frames, None).await.unwrap(),
+            Message::Close(Some(CloseFrame {
+                code: 1000,
+                reason: "bye".into(),
+            }))
+        );
+
+        let frames = [masked(&frame(0x88, b""))];
+        assert_eq!(read_one(&frames, None).await.unwrap(), Message::Close(None));
+    }
+
+    #[tokio::test]
+    async fn rejects_protocol_errors() {
+        let cases = [
+            (vec![masked(&frame(0x09, b""))], "Invalid control frame"),
+            (
+                vec![masked(&frame(0x89, &[0; 126]))],
+                "Invalid control frame",
+            ),
+            (vec![masked(&frame(0x88, b"\x03"))], "Invalid close frame"),
+            (vec![masked(&frame(0x91, b"Hello"))], "Reserved bits set"),
+            (vec![masked(&frame(0xa1, b"Hello"))], "Reserved bits set"),
+            (vec![masked(&frame(0x83, b""))], "Unknown opcode"),
+            (
+                vec![masked(&frame(0x80, b"lo"))],
+                "Unexpected continuation frame",
+            ),
+            (
+                vec![masked(&frame(0x01, b"Hel")), masked(&frame(0x81, b"lo"))],
+                "Expected a continuation frame",
+            ),
+            (vec![masked(&frame(0x81, &[0xff]))], "Invalid UTF-8"),
+        ];
+
+        for (frames, reason) in cases {
+            let err = read(&frames, None).await.pop().unwrap().unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert_eq!(err.to_string(), reason);
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_compressed_frames_without_deflate() {
+        let frames = [masked(&frame(0xc1, &HELLO_DEFLATED))];
+        let err = read_one(&frames, None).await.unwrap_err();
+        assert_eq!(err.to_string(), "Compression wasn't negotiated");
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_frames_from_header() {
+        let mut header = vec![0x82, 0xff];
+        header.extend_from_slice(&(MAX_MESSAGE_SIZE as u64 + 1).to_be_bytes());
+        header.extend_from_slice(&MASK);
+
+        let err = read_one(&[header], None).await.unwrap_err();
+        assert_eq!(err.to_string(), "Message too big");
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_fragmented_messages() {
+        let half = vec![0x2a; MAX_MESSAGE_SIZE / 2 + 1];
+        let frames = [masked(&frame(0x02, &half)), masked(&frame(0x80, &half))];
+
+        let err = read_one(&frames, None).await.unwrap_err();
+        assert_eq!(err.to_string(), "Message too big");
+    }
+
+    #[tokio::test]
+    async fn accepts_messages_at_the_limit() {
+        let payload = vec![0x2a; MAX_MESSAGE_SIZE];
+        let frames = [masked(&frame(0x82, &payload))];
+        assert_eq!(
+            read_one(&frames, None).await.unwrap(),
+            Message::Binary(payload.into())
+        );
+    }
+
+    #[tokio::test]
+    async fn reads_rfc_7692_examples() {
+        let params = Some(deflate(false, false));
+
+        let frames = [masked(&frame(0xc1, &HELLO_DEFLATED))];
+        assert_eq!(read_one(&frames, params).await.unwrap(), text("Hello"));
+
+        // Fragmented, with RSV1 only on the first frame.
+        let frames = [
+            masked(&frame(0x41, &HELLO_DEFLATED[..3])),
+            masked(&frame(0x80, &HELLO_DEFLATED[3..])),
+        ];
+        assert_eq!(read_one(&frames, params).await.unwrap(), text("Hello"));
+
+        // A stored block, and one with BFINAL set.
+        let stored = [
+            0x00, 0x05, 0x00, 0xfa, 0xff, 0x48, 0x65, 0x6c, 0x6c, 0x6f, 0x00,
+        ];
+        let frames = [masked(&frame(0xc1, &stored))];
+        assert_eq!(read_one(&frames, params).await.unwrap(), text("Hello"));
+
+        let last = [0xf3, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00, 0x00];
+        let frames = [masked(&frame(0xc1, &last))];
+        assert_eq!(read_one(&frames, params).await.unwrap(), text("Hello"));
+    }
+
+    #[tokio::test]
+    async fn rejects_rsv1_on_continuation_frames() {
+        let frames = [
+            masked(&frame(0x41, &HELLO_DEFLATED[..3])),
+            masked(&frame(0xc0, &HELLO_DEFLATED[3..])),
+        ];
+        let err = read_one(&frames, Some(deflate(false, false)))
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Unexpected continuation frame");
+    }
+
+    #[tokio::test]
+    async fn inflates_with_context_takeover() {
+        let frames = [
+            masked(&frame(0xc1, &HELLO_DEFLATED)),
+            masked(&frame(0xc1, &HELLO_AGAIN_DEFLATED)),
+        ];
+
+        let messages: Vec<Message> = read(&frames, Some(deflate(false, false)))
+            .await
+            .into_iter()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(messages, [text("Hello"), text("Hello")]);
+
+        // Without the first message to refer back to, the second is garbage.
+        let mut messages = read(&frames, Some(deflate(false, true))).await;
+        let err = messages.pop().unwrap().unwrap_err();
+        assert_eq!(err.to_string(), "Invalid deflate data");
+        assert_eq!(messages.pop().unwrap().unwrap(), text("Hello"));
+    }
+
+    #[tokio::test]
+    async fn rejects_messages_inflating_past_the_limit() {
+        let mut deflater = Deflater::new(deflate(false, false));
+        let bomb = deflater.deflate(&vec![0; MAX_MESSAGE_SIZE + 1]).unwrap();
+        assert!(bomb.len() < 64 * 1024);
+
+        let frames = [masked(&frame(0xc2, &bomb))];
+        let err = read_one(&frames, Some(deflate(false, false)))
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Message too big");
+    }
+
+    #[tokio::test]
+    async fn writes_unmasked_frames() {
+        let bytes = sent(
+            None,
+            vec![
+                text("Hello"),
+                Message::Ping(Bytes::from_static(b"Hello")),
+                Message::Close(Some(CloseFrame {
+                    code: 1000,
+                    reason: "".into(),
+                })),
+            ],
+        )
+        .await;
+
+        assert_eq!(
+            bytes,
+            [
+                frame(0x81, b"Hello"),
+                frame(0x89, b"Hello"),
+                frame(0x88, &[0x03, 0xe8]),
+            ]
+            .concat()
+        );
+    }
+
+    #[tokio::test]
+    async fn writes_extended_lengths() {
+        for len in [125, 126, 65535, 65536] {
+            let payload = vec![0x2a; len];
+            let bytes = sent(None, vec![Message::Binary(payload.clone().into())]).await;
+            assert_eq!(bytes, frame(0x82, &payload));
+        }
+    }
+
+    #[tokio::test]
+    async fn deflates_with_context_takeover() {
+        let bytes = sent(
+            Some(deflate(false, false)),
+            vec![text("Hello"), text("Hello")],
+        )
+        .await;
+        assert_eq!(
+            bytes,
+            [
+                frame(0xc1, &HELLO_DEFLATED),
+                frame(0xc1, &HELLO_AGAIN_DEFLATED),
+            ]
+            .concat()
+        );
+    }
+
+    #[tokio::test]
+    async fn deflates_without_context_takeover() {
+        let bytes = sent(
+            Some(deflate(true, false)),
+            vec![text("Hello"), text("Hello")],
+        )
+        .await;
+        assert_eq!(
+            bytes,
+            [frame(0xc1, &HELLO_DEFLATED), frame(0xc1, &HELLO_DEFLATED)].concat()
+        );
+    }
+
+    #[tokio::test]
+    async fn leaves_control_frames_and_opted_out_messages_uncompressed() {
+        let mut sender = Sender::new(Vec::new(), Some(deflate(false, false)));
+        sender
+            .send(Message::Ping(Bytes::from_static(b"Hello")))
+            .await
+            .unwrap();
+        sender.set_compression(false);
+        sender.send(text("Hello")).await.unwrap();
+
+        assert_eq!(
+            sender.io,
+            [frame(0x89, b"Hello"), frame(0x81, b"Hello")].concat()
+        );
+    }
+
+    #[tokio::test]
+    async fn round_trips_large_compressed_messages() {
+        let payload: String = (0..200_000)
+            .map(|i| char::from(b'a' + (i % 26) as u8))
+            .collect();
+        let params = deflate(false, false);
+
+        let bytes = sent(Some(params), vec![text(&payload), text(&payload)]).await;
+        let mut receiver = Receiver::new(Cursor::new(Vec::new()), Some(params));
+        receiver.buf.extend_from_slice(&masked_all(&bytes));
+
+        assert_eq!(receiver.parse().unwrap(), Some(text(&payload)));
+        assert_eq!(receiver.parse().unwrap(), Some(text(&payload)));
+    }
+
+    /// Masks every frame in a server's output, so it reads as a client's.
+    fn masked_all(mut bytes: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        while !bytes.is_empty() {
+            let (offset, len) = match bytes[1] & 0x7f {
+                126 => (4, u16::from_be_bytes([bytes[2], bytes[3]]) as usize),
+                127 => (
+                    10,
+                    u64::from_be_bytes(bytes[2..10].try_into().unwrap()) as usize,
+                ),
+                len => (2, len as usize),
+            };
+            out.extend(masked(&bytes[..offset + len]));
+            bytes = &bytes[offset + len..];
+        }
+        out
+    }
+}
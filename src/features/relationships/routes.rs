@@ -0,0 +1,164 @@
+use axum::{extract::Path, response::Response, routing::post, Router};
+use sqlx::PgConnection;
+
+use crate::{
+    database::sql::{
+        blocks::is_blocked_between,
+        privacy::{self, get_privacy},
+        relationships::{self, lock_friendship},
+    },
+    middlewares::auth::AuthUser,
+    utils::{error_response, relationship_cache_keys, respond, Failure},
+    DB_POOL, RD_POOL,
+};
+
+/// Who a request is about, by id or by username.
+enum Target {
+    Id(String),
+    Username(String),
+}
+
+impl Target {
+    /// The id of an existing user.
+    async fn resolve(&self, conn: &mut PgConnection) -> Result<String, Failure> {
+        let id: Option<String> = match self {
+            Target::Id(id) => {
+                sqlx::query_scalar(r#"SELECT "id" FROM "users" WHERE "id" = $1"#)
+                    .bind(id)
+                    .fetch_optional(conn)
+                    .await?
+            }
+            Target::Username(username) => {
+                sqlx::query_scalar(
+                    r#"SELECT "id" FROM "users" WHERE lower("username") = lower($1)"#,
+                )
+                .bind(username)
+                .fetch_optional(conn)
+                .await?
+            }
+        };
+
+        id.ok_or(Failure::Rejected("Unknown User"))
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Action {
+    /// Sends a friend request, or accepts theirs if they sent one first.
+    Request,
+    Accept,
+    /// Declines, cancels or unfriends, whatever is between the two.
+    Remove,
+}
+
+impl Action {
+    fn label(self) -> &'static str {
+        match self {
+            Action::Request => "Relationships_RQ",
+            Action::Accept => "Relationships_AC",
+            Action::Remove => "Relationships_RM",
+        }
+    }
+}
+
+async fn apply(auth_user: AuthUser, target: Target, action: Action) -> Response {
+    let pool = DB_POOL.get().unwrap();
+
+    let Ok(mut conn) = RD_POOL
+        .get()
+        .unwrap()
+        .get_multiplexed_async_connection()
+        .await
+    else {
+        return error_response("Internal Server Error");
+    };
+
+    let res = async {
+        let mut tx = pool.begin().await?;
+
+        let user_id = target.resolve(&mut tx).await?;
+        if user_id == auth_user.me {
+            return Err(Failure::Rejected("You can't friend yourself"));
+        }
+
+        let existing = lock_friendship(&mut tx, &auth_user.me, &user_id).await?;
+
+        if !matches!(action, Action::Remove)
+            && is_blocked_between(&mut *tx, &auth_user.me, &user_id).await?
+        {
+            return Err(Failure::Rejected("You can't friend this user"));
+        }
+
+        let friendship = match (action, existing) {
+            (Action::Request, None) => {
+                let audience = get_privacy(&mut conn, &user_id).await.friend_requests;
+                if !privacy::admits(&mut *tx, audience, &user_id, &auth_user.me).await? {
+                    return Err(Failure::Rejected(
+                        "This user isn't accepting friend requests from you",
+                    ));
+                }
+                relationships::create_request(&mut tx, &auth_user.me, &user_id).await?
+            }
+            (Action::Request | Action::Accept, Some(mut friendship))
+                if friendship.accepted_at.is_none() && friendship.user_b == auth_user.me =>
+            {
+                relationships::accept_request(&mut tx, &mut friendship).await?;
+                friendship
+            }
+            (Action::Request, Some(friendship)) if friendship.accepted_at.is_some() => {
+                return Err(Failure::Rejected("Already friends"));
+            }
+            (Action::Request, Some(_)) => {
+                return Err(Failure::Rejected("Friend request already sent"));
+            }
+            (Action::Accept, _) => return Err(Failure::Rejected("No pending friend request")),
+            (Action::Remove, Some(friendship)) => {
+                relationships::remove_friendship(&mut tx, &friendship).await?;
+                friendship
+            }
+            (Action::Remove, None) => return Err(Failure::Rejected("Not friends")),
+        };
+
+        tx.commit().await?;
+
+        Ok::<_, Failure>((friendship.relationship(&auth_user.me), friendship.users()))
+    }
+    .await;
+
+    respond(action.label(), "relationship", res, relationship_cache_keys).await
+}
+
+async fn request(auth_user: AuthUser, Path(id): Path<String>) -> Response {
+    apply(auth_user, Target::Id(id), Action::Request).await
+}
+
+async fn accept(auth_user: AuthUser, Path(id): Path<String>) -> Response {
+    apply(auth_user, Target::Id(id), Action::Accept).await
+}
+
+async fn remove(auth_user: AuthUser, Path(id): Path<String>) -> Response {
+    apply(auth_user, Target::Id(id), Action::Remove).await
+}
+
+async fn request_by_username(auth_user: AuthUser, Path(username): Path<String>) -> Response {
+    apply(auth_user, Target::Username(username), Action::Request).await
+}
+
+async fn accept_by_username(auth_user: AuthUser, Path(username): Path<String>) -> Response {
+    apply(auth_user, Target::Username(username), Action::Accept).await
+}
+
+async fn remove_by_username(auth_user: AuthUser, Path(username): Path<String>) -> Response {
+    apply(auth_user, Target::Username(username), Action::Remove).await
+}
+
+pub fn routes() -> Router {
+    Router::new()
+        .route("/{user_id}", post(request).put(accept).delete(remove))
+        .route(
+            "/username/{username}",
+            post(request_by_username)
+                .put(accept_by_username)
+                .delete(remove_by_username),
+        )
+}
@@ -0,0 +1,351 @@
+use axum::{
+    body::Bytes,
+    extract::{Path, Query},
+    response::{IntoResponse, Response},
+    routing::{get, patch, post},
+    Json, Router,
+};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{
+    database::sql::{
+        blocks::{self, blocks_cache_key, is_blocked_between},
+        channel_members::get_channel,
+        create_channel::{create_channel, DM},
+        get_initial_user::me_user_cache_key,
+        privacy::{self, get_privacy, privacy_cache_key, PrivacyUpdate},
+        relationships::{are_friends, lock_friendship, remove_friendship},
+        users::{get_profile, search_users},
+    },
+    features::realtime::{presence, ratelimit::RateLimiter},
+    middlewares::auth::AuthUser,
+    utils::{
+        channel_cache_keys, decompress::decode_zlib_json, error_response, invalidate_cache,
+        log_error, relationship_cache_keys,
+    },
+    DB_POOL, RD_POOL,
+};
+
+/// Longest search query, in characters.
+pub const MAX_QUERY_LENGTH: usize = 32;
+
+const SEARCH_RESULTS: i64 = 25;
+
+#[derive(Deserialize)]
+struct SearchParams {
+    q: String,
+}
+
+/// Whether `user_id` is an existing user, answering for the handler when
+/// they aren't or the lookup failed.
+async fn user_exists(user_id: &str, label: &str) -> Result<(), Response> {
+    let pool = DB_POOL.get().unwrap();
+
+    let exists_res =
+        sqlx::query_scalar::<_, bool>(r#"SELECT EXISTS (SELECT FROM "users" WHERE "id" = $1)"#)
+            .bind(user_id)
+            .fetch_one(pool)
+            .await;
+
+    match exists_res {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(error_response("Unknown User")),
+        Err(err) => {
+            log_error(label, Some(&err));
+            Err(error_response("Internal Server Error"))
+        }
+    }
+}
+
+/// Returns the DM between the caller and `user_id`, creating it the first
+/// time either of them asks, if `user_id` takes DMs from them.
+async fn open_dm(auth_user: AuthUser, Path(user_id): Path<String>) -> Response {
+    if user_id == auth_user.me {
+        return error_response("You can't DM yourself");
+    }
+
+    if let Err(res) = user_exists(&user_id, "Users_OD_UE").await {
+        return res;
+    }
+
+    let pool = DB_POOL.get().unwrap();
+
+    match is_blocked_between(pool, &auth_user.me, &user_id).await {
+        Ok(false) => {}
+        Ok(true) => return error_response("You can't message this user"),
+        Err(err) => {
+            log_error("Users_OD_BL", Some(&err));
+            return error_response("Internal Server Error");
+        }
+    }
+
+    let audience = match RD_POOL
+        .get()
+        .unwrap()
+        .get_multiplexed_async_connection()
+        .await
+    {
+        Ok(mut conn) => get_privacy(&mut conn, &user_id).await.direct_messages,
+        Err(_) => return error_response("Internal Server Error"),
+    };
+
+    let members = vec![auth_user.me.clone(), user_id];
+
+    let channel_res = async {
+        let mut tx = pool.begin().await?;
+
+        // Both users opening the DM at once must end up in the same channel.
+        let mut pair = members.clone();
+        pair.sort();
+        sqlx::query("SELECT pg_advisory_xact_lock(hashtext($1))")
+            .bind(format!("dm:{}:{}", pair[0], pair[1]))
+            .execute(&mut *tx)
+            .await?;
+
+        let existing: Option<String> = sqlx::query_scalar(
+            r#"SELECT "c"."id"
+            FROM "channels" AS "c"
+              JOIN "channel_members" AS "a" ON "a"."channel_id" = "c"."id" AND "a"."user_id" = $1
+              JOIN "channel_members" AS "b" ON "b"."channel_id" = "c"."id" AND "b"."user_id" = $2
+            WHERE "c"."type" = $3
+            LIMIT 1"#,
+        )
+        .bind(&members[0])
+        .bind(&members[1])
+        .bind(DM)
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        if let Some(id) = existing {
+            if let Some(channel) = get_channel(&mut tx, &id).await? {
+                return Ok(Some((channel, false)));
+            }
+        }
+
+        if !are_friends(&mut *tx, &members[0], &members[1]).await?
+            && !privacy::admits(&mut *tx, audience, &members[1], &members[0]).await?
+        {
+            return Ok(None);
+        }
+
+        let channel = create_channel(&mut tx, DM, None, None, &members).await?;
+        tx.commit().await?;
+        Ok::<_, sqlx::Error>(Some((channel, true)))
+    }
+    .await;
+
+    let (channel, created) = match channel_res {
+        Ok(Some(res)) => res,
+        Ok(None) => return error_response("This user isn't accepting DMs from you"),
+        Err(err) => {
+            log_error("Users_OD_CC", Some(&err));
+            return error_response("Internal Server Error");
+        }
+    };
+
+    if created {
+        invalidate_cache(&channel_cache_keys(&members)).await;
+    }
+
+    Json(json!({ "ok": 1, "channel": channel })).into_response()
+}
+
+/// Blocks a user, ending any friendship or pending request between the two.
+/// They keep seeing the caller as offline and can't befriend or DM them,
+/// while the caller's clients hide their messages in shared groups.
+async fn block_user(auth_user: AuthUser, Path(user_id): Path<String>) -> Response {
+    if user_id == auth_user.me {
+        return error_response("You can't block yourself");
+    }
+
+    if let Err(res) = user_exists(&user_id, "Users_BU_UE").await {
+        return res;
+    }
+
+    let pool = DB_POOL.get().unwrap();
+
+    let block_res = async {
+        let mut tx = pool.begin().await?;
+
+        if let Some(friendship) = lock_friendship(&mut tx, &auth_user.me, &user_id).await? {
+            remove_friendship(&mut tx, &friendship).await?;
+        }
+        blocks::block(&mut tx, &auth_user.me, &user_id).await?;
+
+        tx.commit().await
+    }
+    .await;
+
+    if let Err(err) = block_res {
+        log_error("Users_BU", Some(&err));
+        return error_response("Internal Server Error");
+    }
+
+    let mut keys = relationship_cache_keys(&[auth_user.me.clone(), user_id.clone()]);
+    keys.push(blocks_cache_key(&auth_user.me));
+    invalidate_cache(&keys).await;
+
+    if let Ok(mut conn) = RD_POOL
+        .get()
+        .unwrap()
+        .get_multiplexed_async_connection()
+        .await
+    {
+        presence::refresh(&mut conn, &auth_user.me, &user_id).await;
+    }
+
+    Json(json!({ "ok": 1 })).into_response()
+}
+
+async fn unblock_user(auth_user: AuthUser, Path(user_id): Path<String>) -> Response {
+    let pool = DB_POOL.get().unwrap();
+
+    let unblock_res = async {
+        let mut tx = pool.begin().await?;
+        if !blocks::unblock(&mut tx, &auth_user.me, &user_id).await? {
+            return Ok(None);
+        }
+        let connected = blocks::are_connected(&mut *tx, &auth_user.me, &user_id).await?;
+        tx.commit().await?;
+        Ok::<_, sqlx::Error>(Some(connected))
+    }
+    .await;
+
+    // Blocking ended any friendship, so the two may have nothing left in
+    // common, and then the other side shouldn't learn anything new.
+    let connected = match unblock_res {
+        Ok(Some(connected)) => connected,
+        Ok(None) => return error_response("Not blocked"),
+        Err(err) => {
+            log_error("Users_UU", Some(&err));
+            return error_response("Internal Server Error");
+        }
+    };
+
+    let mut keys = relationship_cache_keys(std::slice::from_ref(&auth_user.me));
+    keys.push(blocks_cache_key(&auth_user.me));
+    invalidate_cache(&keys).await;
+
+    if !connected {
+        return Json(json!({ "ok": 1 })).into_response();
+    }
+
+    if let Ok(mut conn) = RD_POOL
+        .get()
+        .unwrap()
+        .get_multiplexed_async_connection()
+        .await
+    {
+        presence::refresh(&mut conn, &auth_user.me, &user_id).await;
+    }
+
+    Json(json!({ "ok": 1 })).into_response()
+}
+
+/// Finds users by the start of their username or display name, or by
+/// something close to either.
+async fn search(auth_user: AuthUser, Query(params): Query<SearchParams>) -> Response {
+    let query = params.q.trim().to_lowercase();
+    if query.is_empty() || query.chars().count() > MAX_QUERY_LENGTH {
+        return error_response("Searches take 1 to 32 characters");
+    }
+
+    if let Ok(mut conn) = RD_POOL
+        .get()
+        .unwrap()
+        .get_multiplexed_async_connection()
+        .await
+    {
+        let mut limiter = RateLimiter::new(&auth_user.me);
+        if limiter.check(&mut conn, "USER_SEARCH").await.is_err() {
+            return error_response("You are searching too fast");
+        }
+    }
+
+    let pool = DB_POOL.get().unwrap();
+
+    let users_res = async {
+        let mut conn = pool.acquire().await?;
+        search_users(&mut conn, &auth_user.me, &query, SEARCH_RESULTS).await
+    }
+    .await;
+
+    match users_res {
+        Ok(users) => Json(json!({ "ok": 1, "users": users })).into_response(),
+        Err(err) => {
+            log_error("Users_SE", Some(&err));
+            error_response("Internal Server Error")
+        }
+    }
+}
+
+/// A user's public profile, with the friends and channels they share with
+/// the caller.
+async fn profile(auth_user: AuthUser, Path(user_id): Path<String>) -> Response {
+    let pool = DB_POOL.get().unwrap();
+
+    let profile_res = async {
+        let mut conn = pool.acquire().await?;
+        get_profile(&mut conn, &auth_user.me, &user_id).await
+    }
+    .await;
+
+    match profile_res {
+        Ok(Some(profile)) => Json(json!({ "ok": 1, "user": profile })).into_response(),
+        Ok(None) => error_response("Unknown User"),
+        Err(err) => {
+            log_error("Users_PR", Some(&err));
+            error_response("Internal Server Error")
+        }
+    }
+}
+
+/// Changes who can find and reach the caller, and what they share. Fields
+/// left out stay as they are.
+async fn update_privacy(auth_user: AuthUser, body: Bytes) -> Response {
+    let payload: PrivacyUpdate = match decode_zlib_json(body) {
+        Ok(p) => p,
+        Err(_) => return error_response("Invalid Payload"),
+    };
+
+    let pool = DB_POOL.get().unwrap();
+
+    let (privacy, showed_last_seen) =
+        match privacy::update_privacy(pool, &auth_user.me, &payload).await {
+            Ok(updated) => updated,
+            Err(err) => {
+                log_error("Users_UP", Some(&err));
+                return error_response("Internal Server Error");
+            }
+        };
+
+    invalidate_cache(&[
+        privacy_cache_key(&auth_user.me),
+        me_user_cache_key(&auth_user.me),
+    ])
+    .await;
+
+    // Followers learn right away whether `lastSeen` is shared now.
+    if privacy.show_last_seen != showed_last_seen {
+        if let Ok(mut conn) = RD_POOL
+            .get()
+            .unwrap()
+            .get_multiplexed_async_connection()
+            .await
+        {
+            presence::announce(&mut conn, &auth_user.me).await;
+        }
+    }
+
+    Json(json!({ "ok": 1, "privacy": privacy })).into_response()
+}
+
+pub fn routes() -> Router {
+    Router::new()
+        .route("/search", get(search))
+        .route("/@me/privacy", patch(update_privacy))
+        .route("/{id}", get(profile))
+        .route("/{id}/dm", post(open_dm))
+        .route("/{id}/block", post(block_user).delete(unblock_user))
+}
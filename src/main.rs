@@ -1,3 +1,5 @@
+use std::time::Duration;
+
 use axum::{http::Method, routing::get, Router};
 
 use once_cell::sync::OnceCell;
@@ -15,7 +17,11 @@ mod middlewares;
 mod utils;
 mod database;
 
-use features::{auth::routes as auth_routes, realtime::routes as realtime_routes};
+use features::{
+    auth::routes as auth_routes, channels::routes as channel_routes,
+    invites::routes as invite_routes, realtime::routes as realtime_routes,
+    relationships::routes as relationship_routes, users::routes as user_routes,
+};
 
 async fn root() -> &'static str {
     "Sup!"
@@ -23,6 +29,11 @@ async fn root() -> &'static str {
 
 #[tokio::main]
 async fn main() {
+    if std::env::args().nth(1).as_deref() == Some("schema") {
+        println!("{:#}", features::realtime::events::schema());
+        return;
+    }
+
     let _ = dotenvy::dotenv();
     let db_uri = std::env::var("DATABASE_URL").unwrap_or_default();
 
@@ -33,6 +44,19 @@ async fn main() {
 
     RD_POOL.set(rd_client).unwrap();
 
+    // Disconnects a user everywhere, e.g. `cargo run -- kick <user_id>`.
+    if std::env::args().nth(1).as_deref() == Some("kick") {
+        let Some(uid) = std::env::args().nth(2) else {
+            eprintln!("Usage: kick <user_id>");
+            return;
+        };
+
+        let mut conn = RD_POOL.get().unwrap().get_multiplexed_async_connection().await.unwrap();
+        let kicked = features::realtime::registry::kick(&mut conn, &uid).await;
+        println!("Kicked {} connections of {}", kicked, uid);
+        return;
+    }
+
     let pool = PgPoolOptions::new()
         .max_connections(5)
         .connect(&db_uri)
@@ -42,17 +66,32 @@ async fn main() {
     println!("Connected to PostgreSQL : {}", !pool.is_closed());
 
     DB_POOL.set(pool).unwrap();
+
+    features::realtime::outbox::spawn_relay();
+    features::realtime::delivery::spawn();
+    features::realtime::registry::join().await;
+
     let cors_layer = CorsLayer::new()
         .allow_origin(AllowOrigin::list([
             "http://localhost:3000".parse().unwrap(),
             "https://promtuz.xyz".parse().unwrap(),
         ]))
         .allow_credentials(true)
-        .allow_methods(vec![Method::GET, Method::POST]);
+        .allow_methods(vec![
+            Method::GET,
+            Method::POST,
+            Method::PUT,
+            Method::PATCH,
+            Method::DELETE,
+        ]);
 
     let app = Router::new()
         .route("/", get(root))
         .nest("/auth", auth_routes::routes())
+        .nest("/channels", channel_routes::routes())
+        .nest("/invites", invite_routes::routes())
+        .nest("/relationships", relationship_routes::routes())
+        .nest("/users", user_routes::routes())
         .nest("/ws", realtime_routes::routes())
         .layer(
             ServiceBuilder::new()
@@ -60,5 +99,16 @@ async fn main() {
         );
 
     let listener = tokio::net::TcpListener::bind("0.0.0.0:8888").await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    axum::serve(listener, app)
+        .with_graceful_shutdown(features::realtime::shutdown::signal())
+        .await
+        .unwrap();
+
+    // Sockets run their disconnect cleanup, then whatever queries are still
+    // in flight get a moment to finish.
+    features::realtime::shutdown::drain().await;
+    features::realtime::registry::leave().await;
+    tokio::time::timeout(Duration::from_secs(5), DB_POOL.get().unwrap().close())
+        .await
+        .ok();
 }
@@ -1,8 +1,14 @@
 use std::{error::Error, future::Future};
 
-use axum::http;
+use axum::{
+    http,
+    response::{IntoResponse, Response},
+    Json,
+};
 use redis::AsyncCommands;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::RD_POOL;
 
 pub mod decompress;
 
@@ -46,6 +52,91 @@ where
     result
 }
 
+/// Reads a payload field that can be left out, set, or cleared with `null`.
+/// Goes with `#[serde(default, deserialize_with = "nullable")]`.
+pub fn nullable<'de, T, D>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: Deserializer<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+/// The `{ ok: 0 }` body REST handlers answer with when a request can't be served.
+pub fn error_response(message: &str) -> Response {
+    Json(serde_json::json!({ "ok": 0, "error": message })).into_response()
+}
+
+/// Why a REST request didn't go through.
+pub enum Failure {
+    Rejected(&'static str),
+    Database(sqlx::Error),
+}
+
+impl From<sqlx::Error> for Failure {
+    fn from(err: sqlx::Error) -> Self {
+        Failure::Database(err)
+    }
+}
+
+/// Answers with what changed under `key`, once the `cache_keys` of everyone
+/// it affected are dropped.
+pub async fn respond<T: Serialize>(
+    label: &str,
+    key: &str,
+    res: Result<(T, Vec<String>), Failure>,
+    cache_keys: fn(&[String]) -> Vec<String>,
+) -> Response {
+    match res {
+        Ok((value, affected)) => {
+            invalidate_cache(&cache_keys(&affected)).await;
+            Json(serde_json::json!({ "ok": 1, key: value })).into_response()
+        }
+        Err(failure) => failure_response(label, failure),
+    }
+}
+
+/// Answers a request that didn't go through, logging database errors under
+/// `label`.
+pub fn failure_response(label: &str, failure: Failure) -> Response {
+    match failure {
+        Failure::Rejected(message) => error_response(message),
+        Failure::Database(err) => {
+            log_error(label, Some(&err));
+            error_response("Internal Server Error")
+        }
+    }
+}
+
+/// Drops cached query results, so the next read goes to SQL.
+pub async fn invalidate_cache(keys: &[String]) {
+    if keys.is_empty() {
+        return;
+    }
+
+    if let Ok(mut conn) = RD_POOL.get().unwrap().get_multiplexed_async_connection().await {
+        conn.del::<_, ()>(keys).await.ok();
+    }
+}
+
+/// Cache keys that go stale when the channels of `user_ids` change: their
+/// channel list, and the users it brings into their `INIT`.
+pub fn channel_cache_keys(user_ids: &[String]) -> Vec<String> {
+    user_ids
+        .iter()
+        .flat_map(|id| [format!("CACHE:U_CHANNELS:{}", id), format!("CACHE:UF:{}", id)])
+        .collect()
+}
+
+/// Cache keys that go stale when the relationships of `user_ids` change:
+/// their friend list, and the users it brings into their `INIT`.
+pub fn relationship_cache_keys(user_ids: &[String]) -> Vec<String> {
+    user_ids
+        .iter()
+        .flat_map(|id| [format!("CACHE:U_FRNDS:{}", id), format!("CACHE:UF:{}", id)])
+        .collect()
+}
+
 pub fn log_error(label: &str, _err: Option<&sqlx::Error>) {
     let err = _err.unwrap();
     